@@ -0,0 +1,427 @@
+//! Generates interface traits from a TOML protocol description. See `yutani::protocol`.
+
+use std::path::PathBuf;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned, format_ident};
+use syn::{parse_macro_input, parse_quote, spanned::Spanned, GenericArgument, Ident, Item, ItemImpl, ItemMod, LitStr, PathArguments};
+
+mod protocol;
+use protocol::{Arg, ArgType, Enum, Interface, Message, Protocol};
+
+/// Generate a trait for each interface of a protocol within the annotated module.
+///
+/// The path to the TOML protocol description is relative to the directory containing the manifest of the crate
+/// being compiled. The module must contain exactly one implementation of an interface marked `#[display]`, for
+/// which a `display` function is generated to create the display object of each client. The type must implement
+/// `Default`.
+#[proc_macro_attribute]
+pub fn protocol(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(attr as LitStr);
+    let module = parse_macro_input!(item as ItemMod);
+    expand(path, module).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(path: LitStr, mut module: ItemMod) -> syn::Result<TokenStream2> {
+    let Some((_, items)) = &mut module.content else {
+        return Err(syn::Error::new_spanned(module, "#[protocol] must be applied to a module with a body"))
+    };
+    let display = display_constructor(&module.ident, items)?;
+    let file = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default().join(path.value());
+    let source = std::fs::read_to_string(&file)
+        .map_err(|error| syn::Error::new(path.span(), format!("Unable to read {}: {}", file.display(), error)))?;
+    let protocol = Protocol::parse(&source)
+        .map_err(|error| syn::Error::new(path.span(), format!("Invalid protocol {}: {}", file.display(), error)))?;
+
+    // Rebuild when the protocol description changes
+    let file = file.to_string_lossy();
+    items.push(parse_quote!(const _: &str = include_str!(#file);));
+    items.push(Item::Verbatim(display));
+    for interface in &protocol.interfaces {
+        items.push(Item::Verbatim(interface_trait(interface)));
+    }
+    Ok(quote!(#module))
+}
+
+/// Find the implementation marked `#[display]`, removing the marker, and generate a `GlobalBuilderFn` for it.
+fn display_constructor(module: &Ident, items: &mut [Item]) -> syn::Result<TokenStream2> {
+    let mut display: Option<&ItemImpl> = None;
+    for item in items.iter_mut() {
+        let Item::Impl(item) = item else {
+            continue
+        };
+        let Some(position) = item.attrs.iter().position(|attr| attr.path().is_ident("display")) else {
+            continue
+        };
+        let attr = item.attrs.remove(position);
+        if display.is_some() {
+            return Err(syn::Error::new_spanned(attr, "Only one interface implementation may be marked #[display]"))
+        }
+        display = Some(item);
+    }
+    let Some(display) = display else {
+        return Err(syn::Error::new_spanned(module, "One interface implementation must be marked #[display] to be the display object of each client"))
+    };
+    let Some((_, interface, _)) = &display.trait_ else {
+        return Err(syn::Error::new_spanned(&display.self_ty, "#[display] must be applied to the implementation of an interface"))
+    };
+    // The state type is the parameter of the interface trait
+    let state = match interface.segments.last().map(|segment| &segment.arguments) {
+        Some(PathArguments::AngleBracketed(args)) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None
+        }),
+        _ => None
+    };
+    let Some(state) = state else {
+        return Err(syn::Error::new_spanned(interface, "Expected the interface to have the state type as a parameter"))
+    };
+    let ty = &display.self_ty;
+    // Report a missing `Default` implementation at the type rather than within the generated function
+    let value = quote_spanned!(ty.span()=> <#ty as ::std::default::Default>::default());
+    Ok(quote! {
+        /// Create the display object of a new client, as a `GlobalBuilderFn`.
+        pub fn display(
+            _: &mut ::yutani::EventLoop<#state>,
+            _: &mut ::yutani::server::Client<#state>,
+            id: ::yutani::Id,
+            version: u32
+        ) -> ::std::result::Result<::yutani::server::Resident<#state>, ::yutani::WlError<'static>> {
+            let value = #value;
+            ::std::result::Result::Ok(
+                ::yutani::lease::Resident::new(id, <#ty as #interface>::dispatch, <#ty as #interface>::INTERFACE, version, value).into_any()
+            )
+        }
+    })
+}
+
+fn interface_trait(interface: &Interface) -> TokenStream2 {
+    let name = &interface.name;
+    let version = interface.version;
+    let ident = camel_case(name);
+    let doc = format!(" Requests of the `{}` interface.", name);
+    let handlers = interface.requests.iter().map(request_handler);
+    let arms = interface.requests.iter().enumerate().map(|(opcode, request)| request_arm(opcode as u16, request));
+    let events = events_trait(interface, &ident);
+    let enums = interface.enums.iter().map(|enumeration| enum_type(interface, enumeration));
+    quote! {
+        #[doc = #doc]
+        pub trait #ident<T>: ::std::any::Any + ::std::marker::Sized {
+            /// The name of the interface as used on the wire.
+            const INTERFACE: &'static str = #name;
+            /// The version of the interface described by the protocol.
+            const VERSION: u32 = #version;
+            #(#handlers)*
+            /// Decode a request sent to an object of this interface and pass it to the handler, as a `DispatchFn`.
+            #[allow(unused_variables)]
+            fn dispatch(
+                lease: ::yutani::lease::Lease<dyn ::std::any::Any>,
+                event_loop: &mut ::yutani::EventLoop<T>,
+                client: &mut ::yutani::server::Client<T>,
+                message: ::yutani::Message
+            ) -> ::std::result::Result<(), ::yutani::DispatchError> {
+                let object = lease.id();
+                let received = lease.interface();
+                let this = lease.downcast::<Self>()
+                    .ok_or(::yutani::DispatchError::UnexpectedObjectType { object, expected: Self::INTERFACE, received })?;
+                match message.opcode {
+                    #(#arms)*
+                    opcode => Err(::yutani::DispatchError::InvalidRequest { object, interface: Self::INTERFACE, opcode })
+                }
+            }
+        }
+        #events
+        #(#enums)*
+    }
+}
+
+/// An enum is a wrapper of its value, with constants for its entries so that they may be used as bare values.
+fn enum_type(interface: &Interface, enumeration: &Enum) -> TokenStream2 {
+    let name = format!("{}.{}", interface.name, enumeration.name);
+    let ident = enum_ident(&name);
+    let doc = format!(" A value of the `{}` {}.", name, if enumeration.bitfield { "bitfield" } else { "enum" });
+    let entries: Vec<_> = enumeration.entries.iter().map(|entry| {
+        // Entries such as `wl_output.transform.90` start with a digit
+        let prefix = if entry.name.starts_with(|c: char| c.is_ascii_digit()) { "_" } else { "" };
+        format_ident!("{}{}", prefix, entry.name.to_ascii_uppercase())
+    }).collect();
+    let values = enumeration.entries.iter().map(|entry| entry.value);
+    let validate = if enumeration.bitfield {
+        quote!(value & !Self::ENTRIES.iter().fold(0, |all, entry| all | entry) == 0)
+    } else {
+        quote!(Self::ENTRIES.contains(&value))
+    };
+    let bitfield = enumeration.bitfield.then(|| quote! {
+        impl #ident {
+            /// Returns true if every flag set in `flags` is also set in this value.
+            pub const fn contains(self, flags: u32) -> bool {
+                self.0 & flags == flags
+            }
+        }
+        impl ::std::ops::BitOr for #ident {
+            type Output = Self;
+            fn bitor(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+        }
+    });
+    quote! {
+        #[doc = #doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct #ident(u32);
+        impl #ident {
+            #(pub const #entries: u32 = #values;)*
+            const ENTRIES: &'static [u32] = &[#(Self::#entries),*];
+            /// Check that `value` is valid for the enum, failing with `DispatchError::NoVariant` if it is not.
+            pub fn from_u32(value: u32) -> ::std::result::Result<Self, ::yutani::DispatchError> {
+                if #validate {
+                    ::std::result::Result::Ok(Self(value))
+                } else {
+                    ::std::result::Result::Err(::yutani::DispatchError::NoVariant { name: #name, value })
+                }
+            }
+            /// The value as sent on the wire.
+            pub const fn value(self) -> u32 {
+                self.0
+            }
+        }
+        impl ::std::convert::TryFrom<u32> for #ident {
+            type Error = ::yutani::DispatchError;
+            fn try_from(value: u32) -> ::std::result::Result<Self, Self::Error> {
+                Self::from_u32(value)
+            }
+        }
+        impl ::std::convert::TryFrom<i32> for #ident {
+            type Error = ::yutani::DispatchError;
+            fn try_from(value: i32) -> ::std::result::Result<Self, Self::Error> {
+                Self::from_u32(value as u32)
+            }
+        }
+        impl ::std::convert::From<#ident> for u32 {
+            fn from(value: #ident) -> u32 {
+                value.0
+            }
+        }
+        #bitfield
+    }
+}
+
+/// Events are sent through an extension trait on `Lease`, implemented for leases of objects of the interface.
+fn events_trait(interface: &Interface, requests: &Ident) -> Option<TokenStream2> {
+    if interface.events.is_empty() {
+        return None
+    }
+    let ident = format_ident!("{}Events", requests);
+    let doc = format!(" Events of the `{}` interface, sent with a lease of an object implementing `{}`.", interface.name, requests);
+    let signatures = interface.events.iter().map(|event| event_signature(event, snake_case));
+    // The implementation names arguments as locals so that they cannot shadow `stream` and `key`
+    let locals = interface.events.iter().map(|event| event_signature(event, |name| format_ident!("arg_{}", name)));
+    let docs = interface.events.iter().map(|event| {
+        let mut doc = vec![format!(" Send the `{}` event.", event.name)];
+        if event.destructor {
+            doc.push(" The object is destroyed by this event, so it is expected to be deleted once sent.".to_owned());
+        }
+        if event.since > 1 {
+            doc.push(format!(" Nothing is sent to objects older than version {}.", event.since));
+        }
+        quote!(#(#[doc = #doc])*)
+    });
+    let bodies = interface.events.iter().enumerate().map(|(opcode, event)| {
+        let opcode = opcode as u16;
+        let since = (event.since > 1).then(|| {
+            let since = event.since;
+            quote!(if self.version() < #since { return ::std::result::Result::Ok(()) })
+        });
+        let encode = event.args.iter().map(encode_arg);
+        quote! {
+            #since
+            let stream = client.stream();
+            let key = stream.start_message(self.id(), #opcode);
+            #(#encode)*
+            stream.commit(key)
+        }
+    });
+    Some(quote! {
+        #[doc = #doc]
+        pub trait #ident<T> {
+            #(#docs #signatures;)*
+        }
+        impl<T, O: #requests<T>> #ident<T> for ::yutani::lease::Lease<O> {
+            #(#locals { #bodies })*
+        }
+    })
+}
+
+fn event_signature(event: &Message, arg_ident: fn(&str) -> Ident) -> TokenStream2 {
+    let ident = snake_case(&event.name);
+    let args = event.args.iter().map(|arg| {
+        let ident = arg_ident(&arg.name);
+        let ty = match arg.ty {
+            ArgType::String if arg.nullable => quote!(::std::option::Option<&str>),
+            ArgType::String => quote!(&str),
+            ArgType::NewId if arg.interface.is_some() => quote!(::yutani::Id),
+            ArgType::NewId => quote!(&::yutani::NewId),
+            ArgType::Array => quote!(&[u8]),
+            ArgType::Fd => quote!(::yutani::Fd<'static>),
+            _ => arg_type(arg)
+        };
+        quote!(#ident: #ty)
+    });
+    quote! {
+        fn #ident(&self, client: &mut ::yutani::server::Client<T>, #(#args),*) -> ::std::result::Result<(), ::yutani::WlError<'static>>
+    }
+}
+
+fn request_handler(request: &Message) -> TokenStream2 {
+    let ident = snake_case(&request.name);
+    let mut doc = vec![format!(" Handle the `{}` request.", request.name)];
+    if request.destructor {
+        doc.push(" The object is destroyed by this request, so the handler is expected to delete it.".to_owned());
+    }
+    if request.since > 1 {
+        doc.push(format!(" Only sent to objects of version {} or later.", request.since));
+    }
+    let args = request.args.iter().map(|arg| {
+        let ident = snake_case(&arg.name);
+        let ty = arg_type(arg);
+        quote!(#ident: #ty)
+    });
+    quote! {
+        #(#[doc = #doc])*
+        fn #ident(
+            this: ::yutani::lease::Lease<Self>,
+            event_loop: &mut ::yutani::EventLoop<T>,
+            client: &mut ::yutani::server::Client<T>,
+            #(#args),*
+        ) -> ::std::result::Result<(), ::yutani::DispatchError>;
+    }
+}
+
+fn request_arm(opcode: u16, request: &Message) -> TokenStream2 {
+    let ident = snake_case(&request.name);
+    let since = request.since;
+    let guard = (since > 1).then(|| quote!(if this.version() >= #since));
+    let decode = request.args.iter().map(decode_arg);
+    // Arguments are decoded in to locals first as decoding borrows the client
+    let locals = request.args.iter().map(local);
+    quote! {
+        #opcode #guard => {
+            #(#decode)*
+            Self::#ident(this, event_loop, client, #(#locals),*)
+        }
+    }
+}
+
+fn arg_type(arg: &Arg) -> TokenStream2 {
+    if let Some(enumeration) = &arg.enumeration {
+        let ident = enum_ident(enumeration);
+        return quote!(#ident)
+    }
+    match arg.ty {
+        ArgType::Int => quote!(i32),
+        ArgType::Uint => quote!(u32),
+        ArgType::Fixed => quote!(::yutani::Fixed),
+        ArgType::String if arg.nullable => quote!(::yutani::Nullable<::std::string::String>),
+        ArgType::String => quote!(::std::string::String),
+        ArgType::Object if arg.nullable => quote!(::yutani::Nullable<::yutani::Id>),
+        ArgType::Object => quote!(::yutani::Id),
+        ArgType::NewId => quote!(::yutani::NewId),
+        ArgType::Array => quote!(::std::vec::Vec<u8>),
+        ArgType::Fd => quote!(::yutani::File)
+    }
+}
+
+fn decode_arg(arg: &Arg) -> TokenStream2 {
+    let local = local(arg);
+    let interface = match &arg.interface {
+        Some(interface) => quote!(::std::option::Option::Some(#interface)),
+        None => quote!(::std::option::Option::None)
+    };
+    match arg.ty {
+        // The word is the same whether it is read as an `int` or a `uint`
+        ArgType::Int | ArgType::Uint if arg.enumeration.is_some() => {
+            let ty = arg_type(arg);
+            quote!(let #local = #ty::from_u32(client.stream().u32()?)?;)
+        },
+        ArgType::Int => quote!(let #local = client.stream().i32()?;),
+        ArgType::Uint => quote!(let #local = client.stream().u32()?;),
+        ArgType::Fixed => quote!(let #local = client.stream().fixed()?;),
+        ArgType::String if arg.nullable => quote!(let #local = client.stream().string()?;),
+        ArgType::String => quote!(let #local = client.stream().string()?.ok_or(::yutani::WlError::NON_NULLABLE)?;),
+        ArgType::Object if arg.nullable => quote! {
+            let #local = match client.stream().object()? {
+                ::std::option::Option::Some(id) => ::std::option::Option::Some(client.check_object(id, #interface)?),
+                ::std::option::Option::None => ::std::option::Option::None
+            };
+        },
+        ArgType::Object => quote! {
+            let #local = client.stream().non_nullable_object()?;
+            let #local = client.check_object(#local, #interface)?;
+        },
+        ArgType::NewId => match &arg.interface {
+            Some(interface) => quote!(let #local = client.stream().new_id_static(#interface, this.version())?;),
+            None => quote!(let #local = client.stream().new_id()?;)
+        },
+        ArgType::Array => quote!(let #local = client.stream().bytes()?;),
+        ArgType::Fd => quote!(let #local = client.stream().file()?;)
+    }
+}
+
+fn encode_arg(arg: &Arg) -> TokenStream2 {
+    let ident = local(arg);
+    match arg.ty {
+        ArgType::Int | ArgType::Uint if arg.enumeration.is_some() => quote!(stream.send_u32(#ident.value())?;),
+        ArgType::Int => quote!(stream.send_i32(#ident)?;),
+        ArgType::Uint => quote!(stream.send_u32(#ident)?;),
+        ArgType::Fixed => quote!(stream.send_fixed(#ident)?;),
+        ArgType::String if arg.nullable => quote!(stream.send_string(#ident)?;),
+        ArgType::String => quote!(stream.send_string(::std::option::Option::Some(#ident))?;),
+        ArgType::Object if arg.nullable => quote!(stream.send_object(#ident)?;),
+        ArgType::Object => quote!(stream.send_object(::std::option::Option::Some(#ident))?;),
+        // Only the ID is sent when the protocol specifies the interface
+        ArgType::NewId if arg.interface.is_some() => quote!(stream.send_object(::std::option::Option::Some(#ident))?;),
+        ArgType::NewId => quote!(stream.send_new_id(#ident)?;),
+        ArgType::Array => quote!(stream.send_bytes(#ident)?;),
+        ArgType::Fd => quote!(stream.send_file(#ident)?;)
+    }
+}
+
+/// The local an argument is decoded in to or encoded from, prefixed so it cannot shadow the generated locals.
+fn local(arg: &Arg) -> Ident {
+    format_ident!("arg_{}", arg.name)
+}
+
+/// An identifier for a protocol name, which is raw if the name is a Rust keyword.
+fn snake_case(name: &str) -> Ident {
+    syn::parse_str::<Ident>(name).unwrap_or_else(|_| Ident::new_raw(name, Span::call_site()))
+}
+
+/// The type of an enum, such as `WlShmFormat` for `wl_shm.format`.
+fn enum_ident(name: &str) -> Ident {
+    camel_case(&name.replace('.', "_"))
+}
+
+/// Convert a protocol name such as `wl_shm_pool` to the name of a type, `WlShmPool`.
+fn camel_case(name: &str) -> Ident {
+    let name: String = name.split('_')
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars)
+        })
+        .collect();
+    Ident::new(&name, Span::call_site())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(camel_case("wl_shm_pool").to_string(), "WlShmPool");
+        assert_eq!(camel_case("zwp_linux_dmabuf_v1").to_string(), "ZwpLinuxDmabufV1");
+        assert_eq!(snake_case("create_pool").to_string(), "create_pool");
+        assert_eq!(snake_case("move").to_string(), "r#move");
+    }
+}
@@ -0,0 +1,285 @@
+//! The TOML description of a protocol.
+//!
+//! ```toml
+//! [[interface]]
+//! name = "wl_callback"
+//! version = 1
+//!
+//! [[interface.event]]
+//! name = "done"
+//! destructor = true
+//! args = [{ name = "callback_data", type = "uint" }]
+//! ```
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Protocol {
+    #[serde(default, rename = "interface")]
+    pub interfaces: Vec<Interface>
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Interface {
+    pub name: String,
+    pub version: u32,
+    #[serde(default, rename = "request")]
+    pub requests: Vec<Message>,
+    #[serde(default, rename = "event")]
+    pub events: Vec<Message>,
+    #[serde(default, rename = "enum")]
+    pub enums: Vec<Enum>
+}
+/// A request or event. The opcode is the position of the message in its list.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Message {
+    pub name: String,
+    /// The version of the interface the message was added in.
+    #[serde(default = "first_version")]
+    pub since: u32,
+    /// The object is destroyed by the message.
+    #[serde(default)]
+    pub destructor: bool,
+    #[serde(default)]
+    pub args: Vec<Arg>
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Arg {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ArgType,
+    /// The interface of an `object` or `new_id` argument, if the protocol constrains it.
+    pub interface: Option<String>,
+    /// A `string` or `object` argument that may be null.
+    #[serde(default)]
+    pub nullable: bool,
+    /// The enum that an `int` or `uint` argument takes the value of, qualified with the interface name if it
+    /// belongs to another interface. Once parsed, it is always qualified.
+    #[serde(rename = "enum")]
+    pub enumeration: Option<String>
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgType {
+    Int,
+    Uint,
+    Fixed,
+    String,
+    Object,
+    NewId,
+    Array,
+    Fd
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Enum {
+    pub name: String,
+    #[serde(default = "first_version")]
+    pub since: u32,
+    /// Entries are flags that may be combined.
+    #[serde(default)]
+    pub bitfield: bool,
+    pub entries: Vec<Entry>
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub name: String,
+    pub value: u32,
+    #[serde(default = "first_version")]
+    pub since: u32
+}
+fn first_version() -> u32 {
+    1
+}
+
+impl Protocol {
+    /// Parse a protocol description, checking that it is consistent.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut protocol: Self = toml::from_str(source).map_err(|error| error.to_string())?;
+        protocol.validate()?;
+        for interface in &mut protocol.interfaces {
+            let args = interface.requests.iter_mut().chain(&mut interface.events).flat_map(|message| &mut message.args);
+            for enumeration in args.filter_map(|arg| arg.enumeration.as_mut()) {
+                if !enumeration.contains('.') {
+                    *enumeration = format!("{}.{}", interface.name, enumeration);
+                }
+            }
+        }
+        Ok(protocol)
+    }
+    fn validate(&self) -> Result<(), String> {
+        let mut interfaces = HashSet::new();
+        for interface in &self.interfaces {
+            identifier(&interface.name)?;
+            if !interfaces.insert(&interface.name) {
+                return Err(format!("Interface {} is defined more than once.", interface.name))
+            }
+            if interface.version == 0 {
+                return Err(format!("Interface {} must have a version of at least 1.", interface.name))
+            }
+            for (kind, messages) in [("request", &interface.requests), ("event", &interface.events)] {
+                if messages.len() > u16::MAX as usize + 1 {
+                    return Err(format!("Interface {} has too many {}s.", interface.name, kind))
+                }
+                let mut names = HashSet::new();
+                for message in messages {
+                    identifier(&message.name)?;
+                    if !names.insert(&message.name) {
+                        return Err(format!("{}.{} is defined more than once.", interface.name, message.name))
+                    }
+                    if message.since == 0 || message.since > interface.version {
+                        return Err(format!("{}.{} is since version {}, which {} does not have.", interface.name, message.name, message.since, interface.name))
+                    }
+                    self.validate_args(interface, message)?;
+                }
+            }
+            let mut names = HashSet::new();
+            for enumeration in &interface.enums {
+                identifier(&enumeration.name)?;
+                if !names.insert(&enumeration.name) {
+                    return Err(format!("Enum {}.{} is defined more than once.", interface.name, enumeration.name))
+                }
+                if enumeration.since == 0 || enumeration.since > interface.version {
+                    return Err(format!("Enum {}.{} is since version {}, which {} does not have.", interface.name, enumeration.name, enumeration.since, interface.name))
+                }
+                let mut entries = HashSet::new();
+                for entry in &enumeration.entries {
+                    if entry.since < enumeration.since || entry.since > interface.version {
+                        return Err(format!("{}.{}.{} is since version {}, which is not valid for the enum.", interface.name, enumeration.name, entry.name, entry.since))
+                    }
+                    // Entries such as `wl_output.transform.90` may start with a digit
+                    identifier(&format!("_{}", entry.name))?;
+                    if !entries.insert(&entry.name) {
+                        return Err(format!("{}.{}.{} is defined more than once.", interface.name, enumeration.name, entry.name))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn validate_args(&self, interface: &Interface, message: &Message) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for arg in &message.args {
+            identifier(&arg.name)?;
+            let name = || format!("{}.{}.{}", interface.name, message.name, arg.name);
+            if !names.insert(&arg.name) {
+                return Err(format!("Argument {} is defined more than once.", name()))
+            }
+            if arg.interface.is_some() && !matches!(arg.ty, ArgType::Object | ArgType::NewId) {
+                return Err(format!("Argument {} has an interface, but is not an object or new_id.", name()))
+            }
+            if arg.nullable && !matches!(arg.ty, ArgType::String | ArgType::Object) {
+                return Err(format!("Argument {} is nullable, but is not a string or object.", name()))
+            }
+            if let Some(enumeration) = &arg.enumeration {
+                if !matches!(arg.ty, ArgType::Int | ArgType::Uint) {
+                    return Err(format!("Argument {} is an enum, but is not an int or uint.", name()))
+                }
+                match self.resolve_enum(interface, enumeration) {
+                    None => return Err(format!("Argument {} is of enum {}, which is not defined.", name(), enumeration)),
+                    Some((_, enumeration)) if enumeration.bitfield && arg.ty == ArgType::Int => {
+                        return Err(format!("Argument {} is a bitfield, but is not a uint.", name()))
+                    },
+                    _ => ()
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Find the interface and definition of an enum referenced from `interface`.
+    pub fn resolve_enum<'a>(&'a self, interface: &'a Interface, name: &str) -> Option<(&'a Interface, &'a Enum)> {
+        let (interface, name) = match name.split_once('.') {
+            Some((owner, name)) => (self.interfaces.iter().find(|i| i.name == owner)?, name),
+            None => (interface, name)
+        };
+        interface.enums.iter().find(|e| e.name == name).map(|e| (interface, e))
+    }
+}
+
+/// Names must be usable as Rust identifiers once converted to the appropriate case.
+fn identifier(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{:?} is not a valid name.", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let protocol = Protocol::parse(r#"
+            [[interface]]
+            name = "wl_shm"
+            version = 2
+
+            [[interface.request]]
+            name = "create_pool"
+            args = [
+                { name = "id", type = "new_id", interface = "wl_shm_pool" },
+                { name = "fd", type = "fd" },
+                { name = "size", type = "int" }
+            ]
+
+            [[interface.request]]
+            name = "release"
+            since = 2
+            destructor = true
+
+            [[interface.event]]
+            name = "format"
+            args = [{ name = "format", type = "uint", enum = "format" }]
+
+            [[interface.enum]]
+            name = "format"
+            entries = [{ name = "argb8888", value = 0 }, { name = "xrgb8888", value = 1 }]
+        "#).unwrap();
+        let shm = &protocol.interfaces[0];
+        assert_eq!((shm.name.as_str(), shm.version), ("wl_shm", 2));
+        let create_pool = &shm.requests[0];
+        assert_eq!((create_pool.since, create_pool.destructor), (1, false));
+        let types: Vec<_> = create_pool.args.iter().map(|arg| arg.ty).collect();
+        assert_eq!(types, [ArgType::NewId, ArgType::Fd, ArgType::Int]);
+        assert_eq!(create_pool.args[0].interface.as_deref(), Some("wl_shm_pool"));
+        assert!(!create_pool.args[0].nullable);
+        let release = &shm.requests[1];
+        assert_eq!((release.since, release.destructor, release.args.len()), (2, true, 0));
+        assert_eq!(shm.events[0].args[0].enumeration.as_deref(), Some("wl_shm.format"));
+        let (owner, format) = protocol.resolve_enum(shm, "wl_shm.format").unwrap();
+        assert_eq!((owner.name.as_str(), format.entries.len(), format.bitfield), ("wl_shm", 2, false));
+    }
+    #[test]
+    fn invalid() {
+        let interface = |body: &str| format!("[[interface]]\nname = \"a\"\nversion = 1\n{}", body);
+        let cases = [
+            ("[[interface]]\nname = \"a\"\nversion = 1\n[[interface]]\nname = \"a\"\nversion = 1".to_owned(), "more than once"),
+            ("[[interface]]\nname = \"a\"\nversion = 0".to_owned(), "at least 1"),
+            ("[[interface]]\nname = \"a-b\"\nversion = 1".to_owned(), "not a valid name"),
+            (interface("[[interface.request]]\nname = \"r\"\nsince = 2"), "does not have"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"double\" }]"), "unknown variant"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"int\", nullable = true }]"), "nullable"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"uint\", interface = \"a\" }]"), "has an interface"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"uint\", enum = \"e\" }]"), "not defined"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"int\" }, { name = \"x\", type = \"int\" }]"), "more than once"),
+            (interface("[[interface.request]]\nname = \"r\"\ntypo = true"), "unknown field"),
+            (interface("[[interface.request]]\nname = \"r\"\nargs = [{ name = \"x\", type = \"int\", enum = \"e\" }]\n[[interface.enum]]\nname = \"e\"\nbitfield = true\nentries = []"), "not a uint"),
+            (interface("[[interface.enum]]\nname = \"e\"\nentries = [{ name = \"x\", value = 1, since = 2 }]"), "not valid for the enum")
+        ];
+        for (source, expected) in cases {
+            let error = Protocol::parse(&source).unwrap_err();
+            assert!(error.contains(expected), "{:?} should fail with {:?}, not {:?}", source, expected, error);
+        }
+    }
+}
@@ -1,14 +1,115 @@
-use std::{ptr::NonNull, ops::{Deref, DerefMut}, any::Any};
+use std::{ptr::NonNull, ops::{Deref, DerefMut}, any::Any, time::Instant, fmt, borrow::Cow};
 
 use crate::{prelude::*, wire::Message};
 
-pub type DispatchFn<S, C> = fn(Lease<dyn Any>, &mut EventLoop<S>, &mut C, Message) -> Result<(), WlError<'static>>;
+pub type DispatchFn<S, C> = fn(Lease<dyn Any>, &mut EventLoop<S>, &mut C, Message) -> Result<(), DispatchError>;
+/// Cleans up after an object as it is destroyed, before its memory is freed.
+pub type DestructorFn<C> = fn(&mut C, Lease<dyn Any>) -> Result<(), WlError<'static>>;
 
+/// Describes a protocol interface so that its metadata comes from the protocol definition rather than literals.
+pub trait Interface<S, C> {
+    /// The name of the interface as used on the wire.
+    const INTERFACE: &'static str;
+    /// The version of the interface that is implemented.
+    const VERSION: u32;
+    /// Decode and handle a request sent to an object of this interface.
+    fn dispatch(lease: Lease<dyn Any>, event_loop: &mut EventLoop<S>, client: &mut C, message: Message) -> Result<(), DispatchError>;
+}
+
+/// An argument that may be the null object, such as `Nullable<Lease<O>>` for a nullable object argument.
+pub type Nullable<T> = Option<T>;
+
+/// The reason a request could not be dispatched.
+#[derive(Debug)]
+pub enum DispatchError {
+    /// A request was sent to the null object.
+    NullTarget,
+    /// A non-nullable object argument was null.
+    ObjectNull,
+    /// A `new_id` argument named an ID that is already in use.
+    ObjectExists(Id),
+    /// An object argument named an ID with no object.
+    ObjectNonExistent(Id),
+    /// An enum argument had a value with no corresponding variant.
+    NoVariant {
+        /// The name of the enum.
+        name: &'static str,
+        value: u32
+    },
+    /// The opcode does not name a request of the interface, or the request was malformed.
+    InvalidRequest {
+        object: Id,
+        interface: &'static str,
+        opcode: u16
+    },
+    /// An object argument was not of the interface the request expects.
+    UnexpectedObjectType {
+        object: Id,
+        expected: &'static str,
+        received: &'static str
+    },
+    /// A string argument was not valid UTF-8.
+    Utf8Error(std::str::Utf8Error),
+    /// Any other protocol error, such as an interface-specific error raised by a request handler.
+    Protocol(WlError<'static>)
+}
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NullTarget => write!(f, "{}", WlError::NULL_OBJECT.description),
+            Self::ObjectNull => write!(f, "The null object was given where an object is required."),
+            Self::ObjectExists(id) => write!(f, "Object {} already exists.", id),
+            Self::ObjectNonExistent(id) => write!(f, "No object with ID {}.", id),
+            Self::NoVariant { name, value } => write!(f, "{} has no variant with the value {}.", name, value),
+            Self::InvalidRequest { object, interface, opcode } => write!(f, "Invalid request {} to {}@{}.", opcode, interface, object),
+            Self::UnexpectedObjectType { object, expected, received } => write!(f, "Expected object {} to be a {}, but it is a {}.", object, expected, received),
+            Self::Utf8Error(error) => write!(f, "Strings must be valid UTF-8: {}", error),
+            Self::Protocol(error) => write!(f, "{}", error.description)
+        }
+    }
+}
+impl std::error::Error for DispatchError {}
+impl From<WlError<'static>> for DispatchError {
+    fn from(error: WlError<'static>) -> Self {
+        Self::Protocol(error)
+    }
+}
+impl From<DispatchError> for WlError<'static> {
+    /// Describe the error as a `wl_display.error`, using the core error codes for errors not raised by a handler.
+    fn from(error: DispatchError) -> Self {
+        use crate::wire::display_error;
+        let code = match error {
+            DispatchError::Protocol(error) => return error,
+            DispatchError::NullTarget => return WlError::NULL_OBJECT,
+            DispatchError::ObjectExists(_)
+                | DispatchError::ObjectNonExistent(_)
+                | DispatchError::UnexpectedObjectType { .. } => display_error::INVALID_OBJECT,
+            DispatchError::ObjectNull
+                | DispatchError::NoVariant { .. }
+                | DispatchError::InvalidRequest { .. }
+                | DispatchError::Utf8Error(_) => display_error::INVALID_METHOD
+        };
+        WlError {
+            object: Id::DISPLAY,
+            error: code,
+            description: Cow::Owned(error.to_string())
+        }
+    }
+}
+
+/// The shared allocation behind a `Resident` and its `Lease`.
+/// 
+/// `repr(C)` fixes the order and offsets of the fields, and as `value` is last they are the same for `RawLease<T>`
+/// and the unsized `RawLease<dyn Any>`. Erasing the type only adds the vtable of `value` to the pointer, so the data
+/// pointer of a `RawLease<dyn Any>` created from a `RawLease<T>` can be cast back to a `RawLease<T>`.
+#[repr(C)]
 struct RawLease<T: ?Sized> {
     leased: bool,
     id: Id,
     interface: &'static str,
     version: u32,
+    /// When the object was created.
+    created: Instant,
     value: T
 }
 /// An object that maintains ownership that can be leased out. Together, `Resident` and `Lease` provide an
@@ -22,6 +123,7 @@ struct RawLease<T: ?Sized> {
 /// `Rc` and `Weak`, where `Resident` 
 pub struct Resident<T: ?Sized, S, C> {
     dispatch: DispatchFn<S, C>,
+    destructor: Option<DestructorFn<C>>,
     lease: NonNull<RawLease<T>>
 }
 impl<T, S, C> Resident<T, S, C> {
@@ -31,18 +133,30 @@ impl<T, S, C> Resident<T, S, C> {
             id,
             interface,
             version,
+            created: Instant::now(),
             value
         });
         Self {
             dispatch,
+            destructor: None,
             lease: unsafe { NonNull::new_unchecked(Box::leak(boxed)) }
         }
     }
+    /// Create a resident for an object whose interface, version and dispatch function are provided by `I`.
+    pub fn for_interface<I: Interface<S, C>>(id: Id, value: T) -> Self {
+        Self::new(id, I::dispatch, I::INTERFACE, I::VERSION, value)
+    }
+    /// Set a function to run when the object is destroyed.
+    pub fn with_destructor(mut self, destructor: DestructorFn<C>) -> Self {
+        self.destructor = Some(destructor);
+        self
+    }
 }
 impl<T: Any, S, C> Resident<T, S, C> {
     pub fn into_any(self) -> Resident<dyn Any, S, C> {
         let this: Resident<dyn Any, S, C> = Resident {
             dispatch: self.dispatch,
+            destructor: self.destructor,
             lease: self.lease
         };
         // Ensure the old resident doesn't free the RawLease
@@ -68,12 +182,32 @@ impl<T: ?Sized, S, C> Resident<T, S, C> {
     pub fn id(&self) -> Id {
         unsafe { self.lease.as_ref() }.id
     }
+    /// Change the ID of the object, such as when moving it to another client.
+    pub(crate) fn set_id(&mut self, id: Id) {
+        unsafe { self.lease.as_mut() }.id = id
+    }
     pub fn interface(&self) -> &'static str {
         unsafe { self.lease.as_ref() }.interface
     }
     pub fn version(&self) -> u32 {
         unsafe { self.lease.as_ref() }.version
     }
+    /// When the object was created.
+    pub fn created(&self) -> Instant {
+        unsafe { self.lease.as_ref() }.created
+    }
+    /// Change when the object was created, such as to the time given by the clock of the client it is inserted in to.
+    pub(crate) fn set_created(&mut self, created: Instant) {
+        unsafe { self.lease.as_mut() }.created = created
+    }
+    /// Returns true if both residents own the same object.
+    pub fn ptr_eq<U: ?Sized, S2, C2>(&self, other: &Resident<U, S2, C2>) -> bool {
+        self.lease.as_ptr() as *const () == other.lease.as_ptr() as *const ()
+    }
+    /// Returns true if the lease is of the object owned by this resident.
+    pub fn is_leased_by<U: ?Sized>(&self, lease: &Lease<U>) -> bool {
+        self.lease.as_ptr() as *const () == lease.0.as_ptr() as *const ()
+    }
     pub fn lease(&mut self) -> Option<Lease<T>> {
         if unsafe { self.lease.as_ref() }.leased {
             None
@@ -88,6 +222,10 @@ impl<S, C> Resident<dyn Any, S, C> {
     pub fn dispatch(&self) -> DispatchFn<S, C> {
         self.dispatch
     }
+    #[inline]
+    pub fn destructor(&self) -> Option<DestructorFn<C>> {
+        self.destructor
+    }
 }
 impl<T: ?Sized, S, C> Drop for Resident<T, S, C> {
     fn drop(&mut self) {
@@ -111,7 +249,9 @@ impl<T: Any> Lease<T> {
 impl Lease<dyn Any> {
     pub fn downcast<T: Any>(self) -> Option<Lease<T>> {
         if unsafe { self.0.as_ref() }.value.is::<T>() {
-            let lease = Some(Lease(unsafe { NonNull::new_unchecked(self.0.as_ptr().cast()) }));
+            // Safety: The value is a `T`, so the allocation was created as a `RawLease<T>` and discarding the vtable
+            // gives back a valid pointer to it
+            let lease = Some(Lease(self.0.cast::<RawLease<T>>()));
             // Ensure the old lease doesn't free the RawLease
             std::mem::forget(self);
             lease
@@ -130,6 +270,12 @@ impl<T: ?Sized> Lease<T> {
     pub fn version(&self) -> u32 {
         unsafe { self.0.as_ref() }.version
     }
+    /// Returns true if both leases are of the same object, regardless of the type they are viewed as.
+    /// 
+    /// Unlike comparing IDs, this cannot be confused by an ID being reused for a new object.
+    pub fn same_object<U: ?Sized>(&self, other: &Lease<U>) -> bool {
+        self.0.as_ptr() as *const () == other.0.as_ptr() as *const ()
+    }
 }
 impl<T: ?Sized> Deref for Lease<T> {
     type Target = T;
@@ -150,4 +296,129 @@ impl<T: ?Sized> Drop for Lease<T> {
             unsafe { self.0.as_mut() }.leased = false;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    //! The lease tests make no system calls, so that the casts between `RawLease<T>` and `RawLease<dyn Any>` can be
+    //! checked with `cargo +nightly miri test lease::`.
+    use super::*;
+    use crate::wire::display_error;
+    use std::{rc::Rc, cell::Cell};
+
+    type TestResident<T> = Resident<T, (), ()>;
+    fn dispatch(_: Lease<dyn Any>, _: &mut EventLoop<()>, _: &mut (), _: Message) -> Result<(), DispatchError> {
+        Ok(())
+    }
+    /// Counts how many times it has been dropped.
+    struct Tracked {
+        value: u64,
+        drops: Rc<Cell<usize>>
+    }
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1)
+        }
+    }
+    fn tracked(drops: &Rc<Cell<usize>>) -> TestResident<dyn Any> {
+        TestResident::new(Id::new(2), dispatch, "tracked", 1, Tracked { value: 1, drops: drops.clone() }).into_any()
+    }
+
+    #[test]
+    fn downcast_use_drop() {
+        let drops = Rc::new(Cell::new(0));
+        let mut resident = tracked(&drops);
+        let mut lease = resident.lease().unwrap().downcast::<Tracked>().unwrap();
+        assert!(resident.get().is_none() && resident.lease().is_none());
+        lease.value += 1;
+        assert_eq!((lease.id(), lease.interface(), lease.version()), (Id::new(2), "tracked", 1));
+        drop(lease);
+        assert_eq!(resident.get().unwrap().downcast_ref::<Tracked>().unwrap().value, 2);
+        // Erasing the type of a lease again gives back the same object
+        let lease = resident.lease().unwrap().downcast::<Tracked>().unwrap().into_any();
+        assert!(resident.is_leased_by(&lease));
+        drop(lease);
+        assert_eq!(drops.get(), 0);
+        drop(resident);
+        assert_eq!(drops.get(), 1);
+    }
+    #[test]
+    fn downcast_mismatch() {
+        let drops = Rc::new(Cell::new(0));
+        let mut resident = tracked(&drops);
+        assert!(resident.lease().unwrap().downcast::<String>().is_none());
+        // The failed downcast returns the lease rather than freeing the object
+        assert_eq!(drops.get(), 0);
+        assert_eq!(resident.get().unwrap().downcast_ref::<Tracked>().unwrap().value, 1);
+        drop(resident);
+        assert_eq!(drops.get(), 1);
+    }
+    #[test]
+    fn lease_outlives_resident() {
+        let drops = Rc::new(Cell::new(0));
+        let mut resident = tracked(&drops);
+        let mut lease = resident.lease().unwrap().downcast::<Tracked>().unwrap();
+        drop(resident);
+        assert_eq!(drops.get(), 0);
+        lease.value += 1;
+        drop(lease);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn dispatch_error_display() {
+        let id = Id::new(3);
+        let utf8 = String::from_utf8(vec![b'a', 0xFF]).unwrap_err().utf8_error();
+        let cases = [
+            (DispatchError::NullTarget, display_error::INVALID_OBJECT, "Requests cannot be sent to the null object."),
+            (DispatchError::ObjectNull, display_error::INVALID_METHOD, "The null object was given where an object is required."),
+            (DispatchError::ObjectExists(id), display_error::INVALID_OBJECT, "Object 3 already exists."),
+            (DispatchError::ObjectNonExistent(id), display_error::INVALID_OBJECT, "No object with ID 3."),
+            (DispatchError::NoVariant { name: "wl_shm.format", value: 7 }, display_error::INVALID_METHOD, "wl_shm.format has no variant with the value 7."),
+            (DispatchError::InvalidRequest { object: id, interface: "wl_surface", opcode: 12 }, display_error::INVALID_METHOD, "Invalid request 12 to wl_surface@3."),
+            (
+                DispatchError::UnexpectedObjectType { object: id, expected: "wl_surface", received: "wl_region" },
+                display_error::INVALID_OBJECT,
+                "Expected object 3 to be a wl_surface, but it is a wl_region."
+            ),
+            (DispatchError::Utf8Error(utf8), display_error::INVALID_METHOD, "Strings must be valid UTF-8: invalid utf-8 sequence of 1 bytes from index 1"),
+            (DispatchError::Protocol(WlError::NO_OBJECT), WlError::NO_OBJECT.error, "No object with that ID.")
+        ];
+        for (error, code, description) in cases {
+            assert_eq!(error.to_string(), description);
+            let error = WlError::from(error);
+            assert_eq!((error.object, error.error, &*error.description), (Id::DISPLAY, code, description));
+        }
+    }
+    #[test]
+    fn identity() {
+        let drops = Rc::new(Cell::new(0));
+        // Both objects have the same ID, as if it had been reused
+        let mut a = tracked(&drops);
+        let mut b = tracked(&drops);
+        assert!(a.ptr_eq(&a) && !a.ptr_eq(&b));
+        let lease_a = a.lease().unwrap();
+        let lease_b = b.lease().unwrap();
+        assert_eq!(lease_a.id(), lease_b.id());
+        assert!(lease_a.same_object(&lease_a) && !lease_a.same_object(&lease_b));
+        assert!(a.is_leased_by(&lease_a) && !a.is_leased_by(&lease_b));
+        // Identity is kept across changes of type
+        let typed = lease_a.downcast::<Tracked>().unwrap();
+        assert!(a.is_leased_by(&typed) && !typed.same_object(&lease_b));
+        let lease_a = typed.into_any();
+        assert!(a.is_leased_by(&lease_a));
+    }
+    #[test]
+    fn resident_for_interface() {
+        struct Seat;
+        impl Interface<(), ()> for Seat {
+            const INTERFACE: &'static str = "wl_seat";
+            const VERSION: u32 = 7;
+            fn dispatch(lease: Lease<dyn Any>, event_loop: &mut EventLoop<()>, client: &mut (), message: Message) -> Result<(), DispatchError> {
+                dispatch(lease, event_loop, client, message)
+            }
+        }
+        let mut resident = TestResident::for_interface::<Seat>(Id::new(3), 0u8);
+        let lease = resident.lease().unwrap();
+        assert_eq!((lease.id(), lease.interface(), lease.version()), (Id::new(3), "wl_seat", 7));
+    }
+}
@@ -1,44 +1,174 @@
-use std::path::PathBuf;
-
-use prelude::WlError;
+use std::path::{Path, PathBuf};
 
 pub mod lease;
 pub mod server;
 pub mod wire;
 
 pub use prelude::*;
+/// Generate a trait for each interface of a TOML protocol description, within the annotated module.
+/// 
+/// ```ignore
+/// #[yutani::protocol("protocol/wayland.toml")]
+/// pub mod wayland {
+///     #[display]
+///     impl WlDisplay<State> for super::Display {
+///         // ...
+///     }
+/// }
+/// 
+/// let event_loop = Server::event_loop(path, state, wayland::display)?;
+/// ```
+/// 
+/// Each trait has a handler for every request, and a provided `dispatch` function which decodes the arguments of
+/// a request before passing them to its handler. Object arguments are checked to exist and to be of the interface
+/// given by the protocol.
+/// 
+/// Events are sent with methods of an extension trait, such as `WlCallbackEvents`, implemented for the leases of
+/// objects that implement the interface. The opcode of each event is its position in the protocol description.
+/// 
+/// Each enum is a type such as `WlShmFormat`, with a `u32` constant for each entry and `from_u32` to check values
+/// received from clients. Enum arguments are checked when decoded, failing with `DispatchError::NoVariant`.
+/// 
+/// The one implementation marked `#[display]` is the display object of each client. It is a compile error for the
+/// module to have no `#[display]` implementation, or more than one. The generated `display` function creates the
+/// object with `Default`, so the type must implement it. There is no `Server::bind().start(...)` entry point;
+/// instead `display` is a `GlobalBuilderFn`, passed as the display constructor to `Server::event_loop`,
+/// `Server::listen` or `EventLoop::add_client`.
+pub use yutani_macros::protocol;
 pub mod prelude {
-    pub use crate::{Error, lease::Lease, wire::{WlError, EventLoop, Fixed, Id, Message, NewId}};
+    pub use crate::{Error, lease::{Lease, DispatchError, Nullable}, wire::{WlError, EventLoop, Fixed, Id, Message, NewId}};
     pub use syslib::{Fd, File};
 }
 
 /// Find a socket that can be opened for listening.
 /// 
 /// ## Search Order
-/// 1. `WAYLAND_DISPLAY` environment variable
-/// 2. `$XDG_RUNTIME_DIR/wayland-x` where `x` is a value from `0` to `9`.
-/// 3. `wayland.socket`
-pub fn find_free_socket() -> PathBuf {
-    "wayland.socket".into()
+/// 1. `WAYLAND_DISPLAY` environment variable, relative to `$XDG_RUNTIME_DIR` unless it is an absolute path
+/// 2. `$XDG_RUNTIME_DIR/wayland-x` where `x` is a value from `0` to `9`, skipping those in use by another server.
+/// 
+/// As with libwayland, a socket is in use while its `<path>.lock` file is locked. A socket left behind by a server
+/// that has exited can be reused.
+/// 
+/// Fails with `Error::NoRuntimeDir` if `XDG_RUNTIME_DIR` is needed but unset,
+/// or `Error::NoFreeSocket` if every candidate is taken.
+pub fn find_free_socket() -> Result<PathBuf> {
+    use std::env::var_os;
+    let runtime_dir = var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+    if let Some(display) = var_os("WAYLAND_DISPLAY").filter(|display| !display.is_empty()) {
+        let display = PathBuf::from(display);
+        return if display.is_absolute() {
+            Ok(display)
+        } else {
+            runtime_dir.map(|dir| dir.join(display)).ok_or(Error::NoRuntimeDir)
+        }
+    }
+    let runtime_dir = runtime_dir.ok_or(Error::NoRuntimeDir)?;
+    (0..10)
+        .map(|x| runtime_dir.join(format!("wayland-{}", x)))
+        .find(|path| !socket_in_use(path))
+        .ok_or(Error::NoFreeSocket)
+}
+/// Returns true if a server holds the lock of the socket at `path`.
+fn socket_in_use(path: &Path) -> bool {
+    use syslib::open;
+    let lock_path = wire::lock_path(path);
+    match syslib::open(&lock_path, open::Flags::READ_WRITE | open::Flags::CLOSE_ON_EXEC, open::Mode::USER_READ | open::Mode::USER_WRITE) {
+        Ok(lock) => syslib::flock(&lock, syslib::Lock::EXCLUSIVE | syslib::Lock::NON_BLOCKING).is_err(),
+        // Without a lock file there is no lock to hold, but one that cannot be opened may belong to another user
+        Err(_) => lock_path.exists()
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     InvalidSocketPath,
+    /// Another server is listening on the socket.
+    SocketInUse,
+    /// `XDG_RUNTIME_DIR` is not set.
+    NoRuntimeDir,
+    /// Every candidate socket path is already in use.
+    NoFreeSocket,
     DoubleLease,
     BufferEmpty,
     NoGlobal,
     UnsupportedVersion(&'static str, u32),
     NoObject(u32),
+    NoClient,
+    /// The event source has been removed from the `EventLoop`.
+    NoSource,
     DuplicateObject(u32),
     Protocol(WlError<'static>),
+    /// A request handler failed.
+    Dispatch {
+        /// The interface of the object the request was sent to.
+        interface: &'static str,
+        opcode: u16,
+        error: WlError<'static>
+    },
     Utf8(std::string::FromUtf8Error),
     Sys(syslib::Error)
 }
+impl Error {
+    /// Get the protocol error that should be reported to the client, if the client was at fault.
+    pub fn protocol_error(&self) -> Option<&WlError<'static>> {
+        match self {
+            Self::Protocol(error) | Self::Dispatch { error, .. } => Some(error),
+            _ => None
+        }
+    }
+}
 
 impl From<syslib::Error> for Error {
     fn from(err: syslib::Error) -> Self {
         Error::Sys(err)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::{var_os, set_var, remove_var};
+
+    #[test]
+    fn find_free_socket() {
+        // The environment is shared by every thread, so each case is run in turn by one test
+        let saved = (var_os("XDG_RUNTIME_DIR"), var_os("WAYLAND_DISPLAY"));
+        let runtime_dir = std::env::temp_dir().join(format!("yutani-runtime-{}", std::process::id()));
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+        set_var("XDG_RUNTIME_DIR", &runtime_dir);
+        remove_var("WAYLAND_DISPLAY");
+
+        assert_eq!(super::find_free_socket().unwrap(), runtime_dir.join("wayland-0"));
+        let mut servers = vec![wire::Server::listen(runtime_dir.join("wayland-0")).unwrap()];
+        assert_eq!(super::find_free_socket().unwrap(), runtime_dir.join("wayland-1"));
+        for x in 1..10 {
+            servers.push(wire::Server::listen(runtime_dir.join(format!("wayland-{}", x))).unwrap());
+        }
+        assert!(matches!(super::find_free_socket(), Err(Error::NoFreeSocket)));
+        // The socket and lock file left by a server that crashed are not locked, so the socket is reused
+        drop(servers);
+        let stale = runtime_dir.join("wayland-0");
+        std::fs::write(&stale, []).unwrap();
+        std::fs::write(wire::lock_path(&stale), []).unwrap();
+        assert_eq!(super::find_free_socket().unwrap(), stale);
+
+        set_var("WAYLAND_DISPLAY", "display");
+        assert_eq!(super::find_free_socket().unwrap(), runtime_dir.join("display"));
+        set_var("WAYLAND_DISPLAY", "/absolute/display");
+        assert_eq!(super::find_free_socket().unwrap(), PathBuf::from("/absolute/display"));
+        remove_var("XDG_RUNTIME_DIR");
+        assert_eq!(super::find_free_socket().unwrap(), PathBuf::from("/absolute/display"));
+        set_var("WAYLAND_DISPLAY", "display");
+        assert!(matches!(super::find_free_socket(), Err(Error::NoRuntimeDir)));
+        remove_var("WAYLAND_DISPLAY");
+        assert!(matches!(super::find_free_socket(), Err(Error::NoRuntimeDir)));
+
+        std::fs::remove_dir_all(&runtime_dir).unwrap();
+        for (name, value) in [("XDG_RUNTIME_DIR", saved.0), ("WAYLAND_DISPLAY", saved.1)] {
+            match value {
+                Some(value) => set_var(name, value),
+                None => remove_var(name)
+            }
+        }
+    }
+}
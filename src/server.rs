@@ -1,6 +1,6 @@
-use std::{path::Path, any::Any, marker::PhantomData};
+use std::{path::Path, ffi::OsStr, any::Any, marker::PhantomData, borrow::Cow, mem::size_of, ops::{Deref, DerefMut}, time::{Duration, Instant}};
 
-use crate::{prelude::*, wire::{self, *}};
+use crate::{prelude::*, lease::{DispatchFn, Interface}, wire::{self, *}};
 use ahash::{HashMap, HashMapExt};
 use syslib::Fd;
 
@@ -9,25 +9,96 @@ pub mod prelude {
     pub use super::{
         Server,
         Client,
+        ClientId,
+        DispatchErrorHandler,
         Resident
     };
 }
 
 pub type Resident<T> = crate::lease::Resident<dyn Any, T, Client<T>>;
 pub type GlobalBuilderFn<T> = fn(&mut EventLoop<T>, &mut Client<T>, Id, u32) -> Result<Resident<T>, WlError<'static>>;
+/// Called with the requested name when a client attempts to bind a global that does not exist.
+pub type UnknownGlobalFn<T> = fn(&mut Client<T>, u32);
+/// Called for each new client before it is attached to the `EventLoop`, and so before any of its requests are dispatched.
+pub type ConnectFn<T> = fn(&mut EventLoop<T>, &mut Client<T>);
+/// Called when a connection is closed because the server already has the maximum number of clients.
+pub type RejectFn<T> = fn(&mut EventLoop<T>);
+/// Called with the number of objects when a client's object count rises above its limit.
+pub type ObjectLimitFn<T> = fn(&mut Client<T>, usize);
+/// Gives the current time, such as to timestamp objects as they are created.
+pub type ClockFn = fn() -> Instant;
+/// Decides whether a global is visible to a client.
+pub type GlobalFilter<T> = Box<dyn FnMut(&Client<T>, &Global<T>) -> bool>;
+
+/// Handles requests that could not be dispatched to an object.
+/// 
+/// Without a handler the error is sent to the client as the `wl_display.error` given by
+/// `From<DispatchError> for WlError` and the client is disconnected.
+pub trait DispatchErrorHandler<T> {
+    /// Handle a failed request.
+    /// 
+    /// Returning `Ok` continues dispatching the client's requests. Returning `Error::Protocol` sends that
+    /// `wl_display.error` to the client and disconnects it, while any other error disconnects it without an error event.
+    fn handle(&mut self, client: &mut Client<T>, error: DispatchError) -> crate::Result<()>;
+}
 
 pub struct Global<T> {
     pub interface: &'static str,
     pub version: u32,
     pub constructor: GlobalBuilderFn<T>
 }
+impl<T> Clone for Global<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Global<T> {}
 
 pub struct Server<T> {
     server: wire::Server,
     constructor: GlobalBuilderFn<T>,
+    on_connect: Option<ConnectFn<T>>,
+    max_clients: Option<usize>,
+    on_reject: Option<RejectFn<T>>,
+    /// Clients accepted by the server, which may since have disconnected.
+    clients: Vec<ClientId>,
     _marker: PhantomData<T>
 }
 impl<T: 'static> Server<T> {
+    /// Create a server accepting clients from a listening socket.
+    /// 
+    /// `constructor` creates the display object for each new client. The server must be added to an `EventLoop`
+    /// before clients will be accepted.
+    pub fn new(server: wire::Server, constructor: GlobalBuilderFn<T>) -> Self {
+        Self {
+            server,
+            constructor,
+            on_connect: None,
+            max_clients: None,
+            on_reject: None,
+            clients: Vec::new(),
+            _marker: PhantomData
+        }
+    }
+    /// Create a server that will bind and listen to the Unix Domain socket at the specified path.
+    #[inline]
+    pub fn listen<P: AsRef<Path>>(path: P, constructor: GlobalBuilderFn<T>) -> crate::Result<Self> {
+        wire::Server::listen(path).map(|server| Self::new(server, constructor))
+    }
+    /// Set the hook called for each new client before any of its requests are dispatched.
+    pub fn set_connect_hook(&mut self, hook: ConnectFn<T>) {
+        self.on_connect = Some(hook)
+    }
+    /// Limit the number of clients connected at once.
+    /// 
+    /// Connections beyond the limit are accepted and immediately closed.
+    pub fn set_max_clients(&mut self, max_clients: usize) {
+        self.max_clients = Some(max_clients)
+    }
+    /// Set the hook called when a connection is closed for exceeding the client limit.
+    pub fn set_reject_hook(&mut self, hook: RejectFn<T>) {
+        self.on_reject = Some(hook)
+    }
     /// Create an event loop with a `yutani::Server` server attached as an event source.
     /// The server will bind and listen to the Unix Domain socket at the specified path.
     /// The `EventLoop` will contain the specified global state.
@@ -37,12 +108,24 @@ impl<T: 'static> Server<T> {
     #[inline]
     pub fn event_loop<P: AsRef<Path>>(path: P, state: T, constructor: GlobalBuilderFn<T>) -> crate::Result<wire::EventLoop<T>> {
         wire::EventLoop::new(state).and_then(|mut event_loop| {
-            let server = wire::Server::listen(path)
-                .map(|server| Self { server, constructor, _marker: PhantomData })?;
+            let server = Self::listen(path, constructor)?;
+            event_loop.socket_path = server.server.socket_path().map(Path::to_owned);
             event_loop.add(Box::new(server))?;
             Ok(event_loop)
         })
     }
+    /// The path of the Unix domain socket the server is listening on, or `None` when listening over TCP.
+    #[inline]
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.server.socket_path()
+    }
+    /// The file name of the socket, which clients should be given as `WAYLAND_DISPLAY`.
+    /// 
+    /// See `wire::Server::display_name`.
+    #[inline]
+    pub fn display_name(&self) -> Option<&OsStr> {
+        self.server.display_name()
+    }
 }
 impl<T: 'static> EventSource<T> for Server<T> {
     fn fd(&self) -> Fd<'static> {
@@ -50,19 +133,23 @@ impl<T: 'static> EventSource<T> for Server<T> {
     }
 
     fn input(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()> {
-        let fd = syslib::accept(&self.server.socket);
-        let stream = fd
-            .map_err(Error::Sys)
-            .and_then(Stream::new)
-            .map(Client::new)
-            .map(|mut client| {
-                let display = (self.constructor)(event_loop, &mut client, Id::new(1), 1);
-                client.insert(display.unwrap()).unwrap();
-                Box::new(client)
-            });
-        match stream {
-            Ok(stream) => if let Err(e) = event_loop.add(stream) {
-                eprintln!("Failed to add new client to the event loop: {:?}", e)
+        self.clients.retain(|&id| event_loop.contains(id.0));
+        if let Some(max_clients) = self.max_clients {
+            if self.clients.len() >= max_clients {
+                // Accepting and dropping the connection closes it, rather than leaving it to fill the backlog
+                match self.server.accept() {
+                    Ok(_) => if let Some(on_reject) = self.on_reject {
+                        on_reject(event_loop)
+                    },
+                    Err(e) => eprintln!("Failed to accept new client: {:?}", e)
+                }
+                return Ok(())
+            }
+        }
+        match self.server.accept() {
+            Ok(stream) => match event_loop.attach_client(stream, self.constructor, self.on_connect) {
+                Ok(id) => self.clients.push(id),
+                Err(e) => eprintln!("Failed to add new client to the event loop: {:?}", e)
             },
             Err(e) => eprintln!("Failed to accept new client: {:?}", e)
         }
@@ -70,30 +157,173 @@ impl<T: 'static> EventSource<T> for Server<T> {
     }
 }
 
+/// Identifies a client attached to an `EventLoop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(SourceToken);
+impl From<SourceToken> for ClientId {
+    fn from(token: SourceToken) -> Self {
+        Self(token)
+    }
+}
+
+impl<T: 'static> EventLoop<T> {
+    /// Get a client attached to the event loop.
+    /// 
+    /// Returns `None` if there is no such client, or if it is the client currently being dispatched.
+    pub fn client(&mut self, id: ClientId) -> Option<&mut Client<T>> {
+        self.source_mut(id.0)?.as_any_mut()?.downcast_mut()
+    }
+    /// The path of the socket of the server the event loop was created for by `Server::event_loop`.
+    #[inline]
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+    /// The file name of the socket of the server the event loop was created for by `Server::event_loop`, which
+    /// clients should be given as `WAYLAND_DISPLAY`.
+    pub fn display_name(&self) -> Option<&OsStr> {
+        self.socket_path.as_deref()?.file_name()
+    }
+    /// Attach a client communicating over an already connected stream, such as one inherited through `WAYLAND_SOCKET`.
+    /// 
+    /// `constructor` creates the display object for the client.
+    pub fn add_client(&mut self, stream: Stream, constructor: GlobalBuilderFn<T>) -> crate::Result<ClientId> {
+        self.attach_client(stream, constructor, None)
+    }
+    fn attach_client(&mut self, stream: Stream, constructor: GlobalBuilderFn<T>, on_connect: Option<ConnectFn<T>>) -> crate::Result<ClientId> {
+        let id = self.token_for(stream.socket.fd()).into();
+        let mut client = Client::new(stream, id);
+        let display = constructor(self, &mut client, Id::DISPLAY, 1).map_err(Error::Protocol)?;
+        client.insert(display).map_err(Error::Protocol)?;
+        if let Some(on_connect) = on_connect {
+            on_connect(self, &mut client)
+        }
+        self.add(Box::new(client)).map(ClientId::from)
+    }
+    /// Run `f` with another client, then send any events it queued.
+    /// 
+    /// This allows a request from one client to emit events to another. Fails with `Error::NoClient` if there is no
+    /// such client, or if it is the client currently being dispatched.
+    pub fn with_client<R, F: FnOnce(&mut Client<T>) -> R>(&mut self, id: ClientId, f: F) -> crate::Result<R> {
+        let client = self.client(id).ok_or(Error::NoClient)?;
+        let result = f(client);
+        if !client.stream.sendmsg()?.is_flushed() {
+            let fd = client.fd();
+            self.watch_output(fd, true)?;
+        }
+        Ok(result)
+    }
+    /// Add an event source owned by a client, such as a key-repeat timer.
+    /// 
+    /// The source is removed and destroyed when the client disconnects, so that it does not outlive the client.
+    pub fn add_for_client(&mut self, client: ClientId, event_source: Box<dyn EventSource<T>>) -> crate::Result<SourceToken> {
+        if !self.contains(client.0) {
+            return Err(Error::NoClient)
+        }
+        self.add_child(client.0, event_source)
+    }
+    /// Move an object from one client to another.
+    /// 
+    /// The object is removed from `from` as if it were destroyed, sending `wl_display.delete_id`. It is given a new
+    /// server-allocated ID in `to`, as any ID in the client-allocated range may later be chosen by `to` for an object of
+    /// its own. Returns the ID of the object as known by `to`.
+    /// 
+    /// Neither client can be the one currently being dispatched, as it is not available from the `EventLoop`, and
+    /// this fails with `Error::NoClient` if either is.
+    pub fn transfer_object(&mut self, from: ClientId, to: ClientId, id: Id) -> crate::Result<Id> {
+        // Ensure the object has somewhere to go before removing it
+        let new_id = self.client(to).ok_or(Error::NoClient)?.new_id().map_err(Error::Protocol)?;
+        let mut object = self.client(from)
+            .ok_or(Error::NoClient)?
+            .take(id)
+            .map_err(Error::Protocol)?;
+        object.set_id(new_id);
+        self.client(to).ok_or(Error::NoClient)?.insert(object).map_err(Error::Protocol)?;
+        Ok(new_id)
+    }
+}
+
+/// The first ID of the range allocated by the server rather than the client.
+const SERVER_ID_MIN: u32 = 0xFF00_0000;
+
+/// A client connected to the server.
+/// 
+/// ## Re-entrancy
+/// While a request is dispatched the object it was sent to is leased to the handler, which is also given the `Client`.
+/// The handler may insert, lease and remove other objects and send events, such as creating an object in
+/// `wl_registry.bind` and immediately sending events from it.
+/// - Each object is allocated separately from the object map, so inserting objects never moves a leased object.
+/// - The leased object remains in the object map. Inserting another object with its ID fails, and removing it
+///   defers its destructor until the lease is returned.
+/// - Events are only buffered while dispatching, and are sent once all received requests have been handled.
+/// - Other clients can be reached through `EventLoop::with_client`, such as to send focus or selection events to
+///   one client in response to a request from another. The client being dispatched is not available this way.
 pub struct Client<T> {
+    id: ClientId,
     stream: Stream,
     objects: HashMap<Id, Resident<T>>,
     new_id: u32,
-    event_serial: u32
+    event_serial: u32,
+    unknown_global: Option<UnknownGlobalFn<T>>,
+    global_filter: Option<GlobalFilter<T>>,
+    object_limit: Option<(usize, ObjectLimitFn<T>)>,
+    clock: ClockFn,
+    error_handler: Option<Box<dyn DispatchErrorHandler<T>>>,
+    /// Objects removed while leased, which are destroyed once their lease is returned.
+    removed: Vec<Resident<T>>
 }
 impl<T> Client<T> {
-    pub fn new(stream: Stream) -> Self {
+    /// Create a client communicating over `stream`.
+    /// 
+    /// `id` must be the token the client will be identified by once added to the `EventLoop`, as given by
+    /// `EventLoop::token_for`.
+    pub fn new(stream: Stream, id: ClientId) -> Self {
         Self {
+            id,
             stream,
             objects: HashMap::new(),
-            new_id: 0xFF00_0000,
-            event_serial: 0
+            new_id: SERVER_ID_MIN,
+            event_serial: 0,
+            unknown_global: None,
+            global_filter: None,
+            object_limit: None,
+            clock: Instant::now,
+            error_handler: None,
+            removed: Vec::new()
         }
     }
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+    /// The number of file descriptors received from the client that have not yet been consumed by a request.
+    #[inline]
+    pub fn pending_fds(&self) -> usize {
+        self.stream.pending_rx_fds()
+    }
     pub fn stream(&mut self) -> &mut Stream {
         &mut self.stream
     }
-    /// Get a new ID suitable for the next object.
+    /// Get an unused server-allocated ID suitable for the next object.
+    /// 
     /// Failure to create an object with the id may be considered a protocol error under `libwayland`.
-    pub fn new_id(&mut self) -> u32 {
-        let id = self.new_id;
-        self.new_id = self.new_id.checked_add(1).unwrap_or(0xFF00_0000);
-        id
+    /// Fails with `WlError::OOM` if every server-allocated ID is in use.
+    pub fn new_id(&mut self) -> Result<Id, WlError<'static>> {
+        for _ in SERVER_ID_MIN..=u32::MAX {
+            let id = Id::new(self.new_id);
+            self.new_id = self.new_id.checked_add(1).unwrap_or(SERVER_ID_MIN);
+            if !self.objects.contains_key(&id) {
+                return Ok(id)
+            }
+        }
+        Err(WlError::OOM)
+    }
+    /// Ensure that the client has not attempted to create an object with an ID reserved for the server.
+    fn check_client_id(id: Id) -> Result<(), WlError<'static>> {
+        let id: u32 = id.into();
+        if id >= SERVER_ID_MIN {
+            Err(WlError::SERVER_ID)
+        } else {
+            Ok(())
+        }
     }
     /// Get the event serial, then increment it.
     pub fn next_event(&mut self) -> u32 {
@@ -102,67 +332,1127 @@ impl<T> Client<T> {
         event_serial
     }
     /// Insert an object in to the client.
-    pub fn insert(&mut self, object: Resident<T>) -> Result<(), WlError<'static>> {
+    /// 
+    /// Fails with the error for `DispatchError::ObjectExists`, without replacing the existing object, if the ID is
+    /// already in use.
+    pub fn insert(&mut self, mut object: Resident<T>) -> Result<(), WlError<'static>> {
         let id = object.id();
-        if self.objects.insert(id, object).is_some() {
-            Err(WlError::INTERNAL)
-        } else {
-            Ok(())
+        if self.objects.contains_key(&id) {
+            return Err(DispatchError::ObjectExists(id).into())
         }
+        object.set_created((self.clock)());
+        self.objects.insert(id, object);
+        if let Some((limit, hook)) = self.object_limit {
+            if self.objects.len() == limit + 1 {
+                hook(self, self.objects.len())
+            }
+        }
+        Ok(())
+    }
+    /// The number of objects the client has.
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+    /// Iterate over the ID, interface and version of each object of the client, such as to find leaked objects.
+    /// 
+    /// Objects are not leased, so leased objects such as the one being dispatched are included.
+    pub fn objects(&self) -> impl Iterator<Item = (Id, &'static str, u32)> + '_ {
+        self.objects.values().map(|object| (object.id(), object.interface(), object.version()))
+    }
+    /// Iterate over the IDs of objects created more than `age` ago, as told by the client's clock.
+    /// 
+    /// Objects that live for a long time may have been leaked by the client. They are not destroyed, as
+    /// that would violate the protocol, but they can be reported.
+    pub fn objects_older_than(&self, age: Duration) -> impl Iterator<Item = Id> + '_ {
+        let before = (self.clock)().checked_sub(age);
+        self.objects.values()
+            .filter(move |object| before.is_some_and(|before| object.created() < before))
+            .map(|object| object.id())
     }
-    pub fn remove(&mut self, id: Id) -> Result<Resident<T>, WlError<'static>> {
+    /// Set a hook that is called each time the client's number of objects rises above `limit`.
+    /// 
+    /// This allows clients that leak objects to be logged or disconnected.
+    pub fn set_object_limit(&mut self, limit: usize, hook: ObjectLimitFn<T>) {
+        self.object_limit = Some((limit, hook))
+    }
+    /// Set the clock objects are timestamped with as they are inserted, which is `Instant::now` by default.
+    pub fn set_clock(&mut self, clock: ClockFn) {
+        self.clock = clock
+    }
+    /// Create an object for a `new_id` argument of a request to `parent`, returning a lease of it.
+    /// 
+    /// The interface and dispatch function come from `O`. An object created by a request can be no newer than the
+    /// object the request was sent to, so this fails with `WlError::UNSUPPORTED_VERSION` if the requested version
+    /// is newer than `parent` or than `O` implements. Objects created by `wl_registry.bind` are versioned by their
+    /// global instead and are created with `Client::bind`.
+    /// 
+    /// Fails with `DispatchError::ObjectExists` if the ID is already in use, or `WlError::CORRUPT` if the interface
+    /// is not that of `O`.
+    pub fn insert_new<O: Any + Interface<T, Client<T>>, U: ?Sized>(&mut self, parent: &Lease<U>, id: &NewId, value: O) -> Result<Lease<O>, DispatchError> {
+        if id.interface() != O::INTERFACE {
+            return Err(WlError::CORRUPT.into())
+        }
+        if id.version() > parent.version() || id.version() > O::VERSION {
+            return Err(WlError::UNSUPPORTED_VERSION.into())
+        }
+        Self::check_client_id(id.id())?;
+        if self.objects.contains_key(&id.id()) {
+            return Err(DispatchError::ObjectExists(id.id()))
+        }
+        let resident = crate::lease::Resident::new(id.id(), O::dispatch, O::INTERFACE, id.version(), value);
+        self.insert(resident.into_any())?;
+        Ok(self.get(id.id())?)
+    }
+    /// Insert an object in to the client, removing it again if the returned guard is dropped without being committed.
+    /// 
+    /// This allows a request that creates an object and then fails to leave no half-initialised object behind.
+    pub fn insert_guard(&mut self, object: Resident<T>) -> Result<InsertGuard<'_, T>, WlError<'static>> {
+        let id = object.id();
+        self.insert(object)?;
+        Ok(InsertGuard { client: self, id: Some(id) })
+    }
+    /// Create an object with a server-allocated ID, such as an object introduced to the client by an event.
+    /// 
+    /// Returns the ID to send to the client along with a lease of the new object.
+    pub fn new_object<O: Any>(&mut self, dispatch: DispatchFn<T, Client<T>>, interface: &'static str, version: u32, value: O) -> Result<(Id, Lease<O>), WlError<'static>> {
+        let id = self.new_id()?;
+        self.insert(crate::lease::Resident::new(id, dispatch, interface, version, value).into_any())?;
+        let lease = self.lease(id)?.downcast().ok_or(WlError::INTERNAL)?;
+        Ok((id, lease))
+    }
+    /// Replace the object at `id` with a new value, dispatch function and interface, keeping the ID.
+    /// 
+    /// The ID remains in use so no `wl_display.delete_id` is sent, and the destructor of the old object is not run.
+    /// Fails with `WlError::INTERNAL` if the object is leased, such as when replacing the object being dispatched.
+    pub fn replace<O: Any>(&mut self, id: Id, dispatch: DispatchFn<T, Client<T>>, interface: &'static str, version: u32, value: O) -> Result<(), WlError<'static>> {
+        let resident = self.objects.get_mut(&id).ok_or(WlError::NO_OBJECT)?;
+        if resident.get().is_none() {
+            return Err(WlError::INTERNAL)
+        }
+        *resident = crate::lease::Resident::new(id, dispatch, interface, version, value).into_any();
+        resident.set_created((self.clock)());
+        Ok(())
+    }
+    /// Set the hook called when the client attempts to bind a global that does not exist.
+    pub fn set_unknown_global_hook(&mut self, hook: UnknownGlobalFn<T>) {
+        self.unknown_global = Some(hook)
+    }
+    /// Set the handler for requests that could not be dispatched.
+    /// 
+    /// A handler for every client can be set from the connect hook of the `Server`.
+    pub fn set_dispatch_error_handler(&mut self, handler: Box<dyn DispatchErrorHandler<T>>) {
+        self.error_handler = Some(handler)
+    }
+    /// Set the filter deciding which globals are visible to, and can be bound by, the client.
+    pub fn set_global_filter(&mut self, filter: GlobalFilter<T>) {
+        self.global_filter = Some(filter)
+    }
+    /// Returns true if the global filter allows the client to see the global.
+    pub fn global_visible(&mut self, global: &Global<T>) -> bool {
+        // The filter is taken so that it can be given the client
+        let Some(mut filter) = self.global_filter.take() else {
+            return true
+        };
+        let visible = filter(self, global);
+        self.global_filter = Some(filter);
+        visible
+    }
+    /// Send a `wl_registry.global` event to `registry` for each global visible to the client.
+    /// 
+    /// Each global is advertised with its index in `globals` as its name.
+    pub fn advertise_globals(&mut self, registry: Id, globals: &[Global<T>]) -> Result<(), WlError<'static>> {
+        for (name, global) in globals.iter().enumerate() {
+            if !self.global_visible(global) {
+                continue
+            }
+            let key = self.stream.start_message(registry, 0);
+            self.stream.send_u32(name as u32)?;
+            self.stream.send_string(Some(global.interface))?;
+            self.stream.send_u32(global.version)?;
+            self.stream.commit(key)?;
+        }
+        Ok(())
+    }
+    /// Create an object for the global advertised as `name`, as requested by `wl_registry.bind`.
+    /// 
+    /// `global` is the global the compositor advertised as `name`, if any. Binding an unknown global, or one
+    /// hidden by the global filter, is reported to the unknown global hook and fails with `WlError::NO_GLOBAL`.
+    /// Binding to an ID already in use fails as `Client::insert` does, without calling the constructor of the global.
+    pub fn bind(&mut self, event_loop: &mut EventLoop<T>, name: u32, global: Option<Global<T>>, new_id: &NewId) -> Result<(), WlError<'static>> {
+        let global = global
+            .filter(|global| global.interface == new_id.interface())
+            .filter(|global| self.global_visible(global));
+        let Some(global) = global else {
+            if let Some(hook) = self.unknown_global {
+                hook(self, name)
+            }
+            return Err(WlError::NO_GLOBAL)
+        };
+        if new_id.version() > global.version {
+            return Err(WlError::UNSUPPORTED_VERSION)
+        }
+        Self::check_client_id(new_id.id())?;
+        if self.objects.contains_key(&new_id.id()) {
+            return Err(DispatchError::ObjectExists(new_id.id()).into())
+        }
+        let object = (global.constructor)(event_loop, self, new_id.id(), new_id.version())?;
+        self.insert(object)
+    }
+    /// Remove an object from the client without destroying it, sending `wl_display.delete_id`.
+    pub fn take(&mut self, id: Id) -> Result<Resident<T>, WlError<'static>> {
         let resident = self.objects.remove(&id).ok_or(WlError::NO_OBJECT)?;
         let key = self.stream.start_message(Id::DISPLAY, 1);
         self.stream.send_object(Some(id))?;
         self.stream.commit(key)?;
         Ok(resident)
     }
+    /// Destroy an object, running its destructor and sending `wl_display.delete_id`.
+    /// 
+    /// If the object is leased, such as when it is destroying itself, the destructor is run once the request
+    /// being dispatched has been handled.
+    pub fn remove(&mut self, id: Id) -> Result<(), WlError<'static>> {
+        let mut resident = self.take(id)?;
+        match (resident.destructor(), resident.lease()) {
+            (Some(destructor), Some(lease)) => destructor(self, lease),
+            (Some(_), None) => {
+                self.removed.push(resident);
+                Ok(())
+            },
+            (None, _) => Ok(())
+        }
+    }
+    /// Destroy every object of the client, running their destructors, as the client disconnects.
+    /// 
+    /// Objects are destroyed newest first by ID, so the display is destroyed last. No `wl_display.delete_id`
+    /// events are sent. This is called when the client is removed from the `EventLoop`.
+    pub fn disconnect(&mut self) -> Result<(), WlError<'static>> {
+        let mut objects: Vec<_> = std::mem::take(&mut self.objects).into_values().collect();
+        objects.sort_unstable_by_key(|object| std::cmp::Reverse::<u32>(object.id().into()));
+        let mut result = self.destroy_removed();
+        for mut resident in objects {
+            let (Some(destructor), Some(lease)) = (resident.destructor(), resident.lease()) else {
+                continue
+            };
+            if let Err(error) = destructor(self, lease) {
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+    /// Destroy the object `lease` is of, running its destructor and sending `wl_display.delete_id`.
+    /// 
+    /// This allows an object to destroy itself from within its own request handler, in which case the destructor
+    /// runs and the object is freed once the request has been handled and the lease dropped.
+    pub fn delete<U: ?Sized>(&mut self, lease: &Lease<U>) -> Result<(), WlError<'static>> {
+        let id = lease.id();
+        match self.objects.get(&id) {
+            Some(resident) if resident.is_leased_by(lease) => self.remove(id),
+            // The ID has been reused by another object
+            _ => Err(WlError::NO_OBJECT)
+        }
+    }
+    /// An alias of `Client::delete`, for the destructor requests of protocols that call them `drop`.
+    #[inline]
+    pub fn drop<U: ?Sized>(&mut self, lease: &Lease<U>) -> Result<(), WlError<'static>> {
+        self.delete(lease)
+    }
+    /// Run the destructors of objects that were removed while leased.
+    fn destroy_removed(&mut self) -> Result<(), WlError<'static>> {
+        let mut result = Ok(());
+        for mut resident in std::mem::take(&mut self.removed) {
+            let (Some(destructor), Some(lease)) = (resident.destructor(), resident.lease()) else {
+                continue
+            };
+            if let Err(error) = destructor(self, lease) {
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+    /// Pass a failed request to the dispatch error handler, or fail with the error if there is no handler.
+    /// 
+    /// The unread arguments of the request are discarded by `Stream::message` before the next request is read.
+    fn dispatch_error(&mut self, interface: Option<&'static str>, opcode: u16, error: DispatchError) -> crate::Result<()> {
+        let Some(mut handler) = self.error_handler.take() else {
+            return Err(match interface {
+                Some(interface) => Error::Dispatch { interface, opcode, error: error.into() },
+                None => Error::Protocol(error.into())
+            })
+        };
+        let result = handler.handle(self, error);
+        self.error_handler.get_or_insert(handler);
+        result
+    }
     /// Send a protocol error to the client.
-    pub fn error(&mut self, error: &WlError) -> Result<(), WlError> {
+    /// 
+    /// Descriptions too long to fit in a single message are truncated and end with an ellipsis.
+    pub fn error(&mut self, error: &WlError) -> Result<(), WlError<'static>> {
+        // The header, object, error code and string length words and the null terminator
+        const MAX_DESCRIPTION: usize = wire::MAX_MESSAGE_SIZE - 5 * size_of::<u32>() - 1;
+        const ELLIPSIS: &str = "…";
+        let description: Cow<str> = if error.description.len() > MAX_DESCRIPTION {
+            let mut end = MAX_DESCRIPTION - ELLIPSIS.len();
+            while !error.description.is_char_boundary(end) {
+                end -= 1
+            }
+            Cow::Owned(format!("{}{ELLIPSIS}", &error.description[..end]))
+        } else {
+            Cow::Borrowed(&error.description)
+        };
         let key = self.stream.start_message(Id::DISPLAY, 0);
         self.stream.send_object(Some(error.object))?;
         self.stream.send_u32(error.error)?;
-        self.stream.send_string(Some(&error.description))?;
+        self.stream.send_string(Some(&description))?;
         self.stream.commit(key)
     }
     pub fn get_mut(&mut self, id: Id) -> Option<&mut Resident<T>> {
         self.objects.get_mut(&id)
     }
+    /// Lease an object as its concrete type.
+    /// 
+    /// Fails with `WlError::NO_OBJECT` if there is no such object, `WlError::INTERNAL` if it is already leased,
+    /// or an `invalid_object` error naming the interface of the object if it is not an `O`.
+    pub fn get<O: Any>(&mut self, id: Id) -> Result<Lease<O>, WlError<'static>> {
+        let lease = self.objects.get_mut(&id).ok_or(WlError::NO_OBJECT)?.lease().ok_or(WlError::INTERNAL)?;
+        let interface = lease.interface();
+        lease.downcast().ok_or_else(|| WlError {
+            object: Id::DISPLAY,
+            error: wire::display_error::INVALID_OBJECT,
+            description: Cow::Owned(format!("Object {} is a {}, which was not expected here.", id, interface))
+        })
+    }
+    /// Lease the object named by a non-nullable object argument, as read by `Stream::object`.
+    /// 
+    /// Fails with `DispatchError::ObjectNull` if the argument is the null object, `DispatchError::ObjectNonExistent`
+    /// if there is no such object, or `DispatchError::UnexpectedObjectType` if it is not an `O`.
+    pub fn object_arg<O: Any + Interface<T, Client<T>>>(&mut self, id: Option<Id>) -> Result<Lease<O>, DispatchError> {
+        let id = id.ok_or(DispatchError::ObjectNull)?;
+        let lease = self.objects.get_mut(&id)
+            .ok_or(DispatchError::ObjectNonExistent(id))?
+            .lease()
+            .ok_or(DispatchError::Protocol(WlError::INTERNAL))?;
+        let received = lease.interface();
+        lease.downcast().ok_or(DispatchError::UnexpectedObjectType { object: id, expected: O::INTERFACE, received })
+    }
+    /// Lease the object named by a nullable object argument, as read by `Stream::object`.
+    /// 
+    /// The null object is `None`, otherwise this fails as `Client::object_arg` does.
+    pub fn nullable_object_arg<O: Any + Interface<T, Client<T>>>(&mut self, id: Option<Id>) -> Result<Nullable<Lease<O>>, DispatchError> {
+        id.map(|id| self.object_arg(Some(id))).transpose()
+    }
+    /// Check that an object argument names an object, of `interface` if the protocol constrains it.
+    /// 
+    /// Unlike `Client::object_arg` the object is not leased, so it may be the object the request was sent to.
+    /// Fails with `DispatchError::ObjectNonExistent` or `DispatchError::UnexpectedObjectType`.
+    pub fn check_object(&self, id: Id, interface: Option<&'static str>) -> Result<Id, DispatchError> {
+        let object = self.objects.get(&id).ok_or(DispatchError::ObjectNonExistent(id))?;
+        match interface {
+            Some(expected) if object.interface() != expected => {
+                Err(DispatchError::UnexpectedObjectType { object: id, expected, received: object.interface() })
+            },
+            _ => Ok(id)
+        }
+    }
     pub fn lease(&mut self, id: Id) -> Result<Lease<dyn Any>, WlError<'static>> {
         self.objects.get_mut(&id).and_then(Resident::lease).ok_or(WlError::INTERNAL)
     }
 }
-impl<T> EventSource<T> for Client<T> {
+/// An object inserted in to a client that will be removed on drop unless committed.
+/// 
+/// The guard dereferences to the client so that it remains usable while the guard is alive.
+pub struct InsertGuard<'a, T> {
+    client: &'a mut Client<T>,
+    id: Option<Id>
+}
+impl<'a, T> InsertGuard<'a, T> {
+    /// Keep the inserted object.
+    pub fn commit(mut self) {
+        self.id = None
+    }
+}
+impl<'a, T> Deref for InsertGuard<'a, T> {
+    type Target = Client<T>;
+    fn deref(&self) -> &Self::Target {
+        self.client
+    }
+}
+impl<'a, T> DerefMut for InsertGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client
+    }
+}
+impl<'a, T> Drop for InsertGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            // Rolling back an insertion does not destroy the object, so no `wl_display.delete_id` is sent
+            self.client.objects.remove(&id);
+        }
+    }
+}
+
+impl<T: 'static> EventSource<T> for Client<T> {
     fn fd(&self) -> Fd<'static> {
         self.stream.socket.fd().extend()
     }
+    fn destroy(&mut self, _event_loop: &mut EventLoop<T>) {
+        if let Err(e) = self.disconnect() {
+            eprintln!("Failed to destroy the objects of a disconnected client: {:?}", e)
+        }
+    }
+    fn as_any_mut(&mut self) -> Option<&mut (dyn Any + 'static)> {
+        Some(self)
+    }
 
     fn input(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()> {
         let result = if self.stream.recvmsg()? {
-            let dispatch_result = (|| {
+            let dispatch_result = (|| -> crate::Result<()> {
                 while let Some(message) = self.stream.message() {
-                    let message = message?;
-                    if let Some(resident) = self.get_mut(message.object) {
-                        let dispatch = resident.dispatch();
-                        let lease = resident.lease().ok_or(WlError::INTERNAL)?;
-                        dispatch(lease, event_loop, self, message)?
-                    } else {
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(error) => {
+                            self.dispatch_error(None, 0, error)?;
+                            continue
+                        }
+                    };
+                    let opcode = message.opcode;
+                    let Some(resident) = self.get_mut(message.object) else {
                         // TODO: if the object was recently deleted just ignore the request as requests may have been in-flight still
-                        return Err(WlError::NO_OBJECT)
+                        self.dispatch_error(None, opcode, DispatchError::ObjectNonExistent(message.object))?;
+                        continue
+                    };
+                    let dispatch = resident.dispatch();
+                    let interface = resident.interface();
+                    let lease = resident.lease().ok_or(Error::Protocol(WlError::INTERNAL))?;
+                    if let Err(error) = dispatch(lease, event_loop, self, message) {
+                        self.dispatch_error(Some(interface), opcode, error)?
                     }
+                    self.destroy_removed().map_err(|error| Error::Dispatch { interface, opcode, error })?
                 }
                 Ok(())
             })();
-            if let Err(error) = dispatch_result {
-                let _ = self.error(&error);
-                Err(Error::Protocol(error))
-            } else {
-                Ok(())
+            if let Err(error) = &dispatch_result {
+                if let Some(error) = error.protocol_error() {
+                    let _ = self.error(error);
+                }
             }
+            dispatch_result
         } else {
             Ok(())
         };
-        self.stream.sendmsg()?;
+        if !self.stream.sendmsg()?.is_flushed() {
+            event_loop.watch_output(self.fd(), true)?;
+        }
         result
     }
-}
\ No newline at end of file
+    fn output(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()> {
+        if self.stream.sendmsg()?.is_flushed() {
+            event_loop.watch_output(self.fd(), false)?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::tests::{socket_path, stream_pair, send_words, header, new_id, golden, sent_bytes};
+    use std::{rc::Rc, cell::RefCell};
+
+    /// Records the `uint` argument of opcode 0, and fails opcode 1 without reading its arguments.
+    fn probe(_: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, message: Message) -> Result<(), DispatchError> {
+        match message.opcode {
+            0 => {
+                let value = client.stream().u32()?;
+                event_loop.state.push(value);
+                Ok(())
+            },
+            opcode => Err(DispatchError::InvalidRequest { object: message.object, interface: "probe", opcode })
+        }
+    }
+    /// Create a client with a probe object as its display, returning the peer to send requests from.
+    fn client() -> (EventLoop<Vec<u32>>, Client<Vec<u32>>, Stream) {
+        let event_loop = EventLoop::new(Vec::new()).unwrap();
+        let (stream, peer) = stream_pair();
+        let id = event_loop.token_for(stream.socket.fd()).into();
+        let mut client = Client::new(stream, id);
+        client.insert(crate::lease::Resident::new(Id::DISPLAY, probe, "probe", 1, ()).into_any()).unwrap();
+        (event_loop, client, peer)
+    }
+
+    fn probe_display(_: &mut EventLoop<Vec<u32>>, _: &mut Client<Vec<u32>>, id: Id, version: u32) -> Result<Resident<Vec<u32>>, WlError<'static>> {
+        Ok(crate::lease::Resident::new(id, probe, "probe", version, ()).into_any())
+    }
+
+    /// The IDs of destroyed objects, in the order their destructors ran.
+    type Log = Rc<RefCell<Vec<u32>>>;
+    fn log_destroyed(_: &mut Client<Vec<u32>>, lease: Lease<dyn Any>) -> Result<(), WlError<'static>> {
+        let id = lease.id().into();
+        lease.downcast::<Log>().unwrap().borrow_mut().push(id);
+        Ok(())
+    }
+    fn logged(id: u32, log: &Log) -> Resident<Vec<u32>> {
+        crate::lease::Resident::new(Id::new(id), probe, "logged", 1, log.clone()).with_destructor(log_destroyed).into_any()
+    }
+
+    /// Read the `wl_display.error` sent to `peer`, returning the object it is about and its code.
+    fn received_error(peer: &mut Stream) -> (Id, u32) {
+        assert!(peer.recvmsg().unwrap());
+        let message = peer.message().unwrap().unwrap();
+        assert_eq!((message.object, message.opcode), (Id::DISPLAY, 0));
+        (peer.object().unwrap().unwrap(), peer.u32().unwrap())
+    }
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+    impl DispatchErrorHandler<Vec<u32>> for Recorder {
+        fn handle(&mut self, _: &mut Client<Vec<u32>>, error: DispatchError) -> crate::Result<()> {
+            self.0.borrow_mut().push(format!("{:?}", error));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dispatch_error_handler_receives_error() {
+        let (mut event_loop, mut client, mut peer) = client();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        client.set_dispatch_error_handler(Box::new(Recorder(errors.clone())));
+        // The failed request leaves its arguments unread, and a request to an unknown object follows
+        send_words(&mut peer, &[1, header(4, 1), 7, 8, 5, header(2, 0), 1, header(3, 0), 42]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(event_loop.state, [42]);
+        assert_eq!(*errors.borrow(), [
+            r#"InvalidRequest { object: Id(1), interface: "probe", opcode: 1 }"#,
+            "ObjectNonExistent(Id(5))"
+        ]);
+    }
+    #[test]
+    fn null_object_request_reaches_handler() {
+        // Without a handler the client is sent `wl_display.error` with the `invalid_object` code
+        let (mut event_loop, mut unhandled, mut peer) = client();
+        send_words(&mut peer, &[0, header(2, 0)]);
+        assert!(unhandled.input(&mut event_loop).is_err());
+        assert_eq!(received_error(&mut peer), (Id::DISPLAY, wire::display_error::INVALID_OBJECT));
+
+        let (mut event_loop, mut client, mut peer) = client();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        client.set_dispatch_error_handler(Box::new(Recorder(errors.clone())));
+        send_words(&mut peer, &[0, header(3, 0), 7, 1, header(3, 0), 42]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(event_loop.state, [42]);
+        assert_eq!(*errors.borrow(), ["NullTarget"]);
+    }
+    #[test]
+    fn display_error_matches_libwayland() {
+        let (_, mut client, peer) = client();
+        client.error(&WlError { object: Id::DISPLAY, error: 1, description: Cow::Borrowed("invalid method") }).unwrap();
+        assert_eq!(sent_bytes(client.stream(), &peer), golden("wl_display.error"));
+    }
+    #[test]
+    fn dispatch_error_without_handler_disconnects() {
+        let (mut event_loop, mut client, mut peer) = client();
+        send_words(&mut peer, &[1, header(2, 1)]);
+        assert!(matches!(client.input(&mut event_loop), Err(Error::Dispatch { interface: "probe", opcode: 1, .. })));
+    }
+    #[test]
+    fn transfer_object() {
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        let (stream, _from_peer) = stream_pair();
+        let from = event_loop.add_client(stream, probe_display).unwrap();
+        let (stream, mut to_peer) = stream_pair();
+        let to = event_loop.add_client(stream, probe_display).unwrap();
+        let id = Id::new(3);
+        event_loop.with_client(from, |client| client.insert(crate::lease::Resident::new(id, probe, "probe", 1, ()).into_any())).unwrap().unwrap();
+
+        let new_id = event_loop.transfer_object(from, to, id).unwrap();
+        let raw_id: u32 = new_id.into();
+        assert!(raw_id >= SERVER_ID_MIN);
+        assert!(event_loop.client(from).unwrap().get_mut(id).is_none());
+        // The ID remains free for the receiving client to allocate itself
+        assert!(event_loop.client(to).unwrap().get_mut(id).is_none());
+
+        send_words(&mut to_peer, &[raw_id, header(3, 0), 42]);
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.state, [42]);
+    }
+    #[test]
+    fn bind_unknown_global() {
+        use std::cell::Cell;
+        thread_local! {
+            static UNKNOWN: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+            static CONSTRUCTED: Cell<u32> = const { Cell::new(0) };
+        }
+        fn unknown_global(_: &mut Client<Vec<u32>>, name: u32) {
+            UNKNOWN.with(|unknown| unknown.borrow_mut().push(name))
+        }
+        fn counted(event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, id: Id, version: u32) -> Result<Resident<Vec<u32>>, WlError<'static>> {
+            CONSTRUCTED.with(|constructed| constructed.set(constructed.get() + 1));
+            probe_display(event_loop, client, id, version)
+        }
+        /// Binds the only global, which is named 0.
+        fn registry(_: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            let name = client.stream().u32()?;
+            let new_id = client.stream().new_id()?;
+            let global = (name == 0).then_some(Global { interface: "probe", version: 2, constructor: counted });
+            Ok(client.bind(event_loop, name, global, &new_id)?)
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        client.set_unknown_global_hook(unknown_global);
+        client.insert(crate::lease::Resident::new(Id::new(2), registry, "registry", 1, ()).into_any()).unwrap();
+        // The client is sent an error rather than the server panicking
+        let key = peer.start_message(Id::new(2), 0);
+        peer.send_u32(7).unwrap();
+        peer.send_new_id(&new_id(3, "probe", 1)).unwrap();
+        peer.commit(key).unwrap();
+        assert!(peer.sendmsg().unwrap().is_flushed());
+        assert!(matches!(client.input(&mut event_loop), Err(Error::Dispatch { interface: "registry", opcode: 0, .. })));
+        assert_eq!(received_error(&mut peer), (Id::DISPLAY, wire::display_error::INVALID_OBJECT));
+
+        let global = Global { interface: "probe", version: 2, constructor: counted };
+        // A global of another interface is not the global the client asked for
+        let error = client.bind(&mut event_loop, 0, Some(global), &new_id(3, "other", 1)).unwrap_err();
+        assert_eq!(error.error, WlError::NO_GLOBAL.error);
+        client.set_global_filter(Box::new(|_, _| false));
+        let error = client.bind(&mut event_loop, 0, Some(global), &new_id(3, "probe", 1)).unwrap_err();
+        assert_eq!(error.error, WlError::NO_GLOBAL.error);
+        // The hook is told of each failed bind
+        assert_eq!(UNKNOWN.with(|unknown| unknown.take()), [7, 0, 0]);
+
+        client.set_global_filter(Box::new(|_, _| true));
+        let error = client.bind(&mut event_loop, 0, Some(global), &new_id(3, "probe", 3)).unwrap_err();
+        assert_eq!(error.error, WlError::UNSUPPORTED_VERSION.error);
+        client.bind(&mut event_loop, 0, Some(global), &new_id(3, "probe", 2)).unwrap();
+        assert_eq!(client.get_mut(Id::new(3)).map(|object| object.version()), Some(2));
+        // An ID already in use fails without constructing another object
+        let error = client.bind(&mut event_loop, 0, Some(global), &new_id(3, "probe", 1)).unwrap_err();
+        assert_eq!(error.error, WlError::from(DispatchError::ObjectExists(Id::new(3))).error);
+        assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    }
+    #[test]
+    fn disconnect_runs_destructors() {
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        let (stream, peer) = stream_pair();
+        let client = event_loop.add_client(stream, probe_display).unwrap();
+        let log = Log::default();
+        event_loop.with_client(client, |client| {
+            for id in [3, 2, 5] {
+                client.insert(logged(id, &log)).unwrap()
+            }
+        }).unwrap();
+        // The client closing its end removes it from the event loop
+        drop(peer);
+        event_loop.wait(1000).unwrap();
+        assert!(event_loop.client(client).is_none());
+        assert_eq!(*log.borrow(), [5, 3, 2]);
+    }
+    #[test]
+    fn get_checks_type() {
+        let (_, mut client, _peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(2), probe, "number", 1, 5u32).into_any()).unwrap();
+        let error = client.get::<String>(Id::new(2)).err().unwrap();
+        assert_eq!(error.error, wire::display_error::INVALID_OBJECT);
+        assert!(error.description.contains("number"));
+        // The failed downcast returns the lease
+        let mut lease = client.get::<u32>(Id::new(2)).unwrap();
+        *lease += 1;
+        assert_eq!(client.get::<u32>(Id::new(2)).err().unwrap().error, WlError::INTERNAL.error);
+        drop(lease);
+        assert_eq!(*client.get::<u32>(Id::new(2)).unwrap(), 6);
+        assert_eq!(client.get::<u32>(Id::new(3)).err().unwrap().description, WlError::NO_OBJECT.description);
+    }
+    struct Child;
+    impl Interface<Vec<u32>, Client<Vec<u32>>> for Child {
+        const INTERFACE: &'static str = "child";
+        const VERSION: u32 = 3;
+        fn dispatch(lease: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, message: Message) -> Result<(), DispatchError> {
+            probe(lease, event_loop, client, message)
+        }
+    }
+    #[test]
+    fn insert_new() {
+        fn is_error(result: Result<Lease<Child>, DispatchError>, expected: WlError) -> bool {
+            matches!(result, Err(DispatchError::Protocol(error)) if error.description == expected.description)
+        }
+        let (_, mut client, _peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(2), probe, "probe", 2, ()).into_any()).unwrap();
+        client.insert(crate::lease::Resident::new(Id::new(3), probe, "probe", 4, ()).into_any()).unwrap();
+        let parent = client.lease(Id::new(2)).unwrap();
+        let newer_parent = client.lease(Id::new(3)).unwrap();
+
+        let child = client.insert_new(&parent, &new_id(4, "child", 2), Child).unwrap();
+        assert_eq!((child.id(), child.interface(), child.version()), (Id::new(4), "child", 2));
+        drop(child);
+        // The existing object is kept
+        assert!(matches!(client.insert_new(&parent, &new_id(4, "child", 1), Child), Err(DispatchError::ObjectExists(id)) if id == Id::new(4)));
+        assert_eq!(client.get_mut(Id::new(4)).unwrap().version(), 2);
+
+        assert!(is_error(client.insert_new(&parent, &new_id(5, "child", 3), Child), WlError::UNSUPPORTED_VERSION));
+        assert!(is_error(client.insert_new(&newer_parent, &new_id(5, "child", 4), Child), WlError::UNSUPPORTED_VERSION));
+        assert!(is_error(client.insert_new(&parent, &new_id(5, "other", 1), Child), WlError::CORRUPT));
+        assert!(is_error(client.insert_new(&parent, &new_id(SERVER_ID_MIN, "child", 1), Child), WlError::SERVER_ID));
+        assert_eq!(client.object_count(), 4);
+    }
+    #[test]
+    fn delete_self_from_handler() {
+        fn destroy(lease: Lease<dyn Any>, _: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            client.drop(&lease)?;
+            // The object is only destroyed once the request has been handled
+            assert!(lease.downcast::<Log>().unwrap().borrow().is_empty());
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        let log = Log::default();
+        client.insert(crate::lease::Resident::new(Id::new(2), destroy, "logged", 1, log.clone()).with_destructor(log_destroyed).into_any()).unwrap();
+        send_words(&mut peer, &[2, header(2, 0)]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(*log.borrow(), [2]);
+        assert_eq!(Rc::strong_count(&log), 1);
+        assert!(client.get_mut(Id::new(2)).is_none());
+
+        let delete_id: Vec<u8> = [1, header(3, 1), 2].iter().flat_map(|word| word.to_ne_bytes()).collect();
+        let mut bytes = vec![0; delete_id.len()];
+        assert_eq!(syslib::read(&peer.socket, &mut bytes).unwrap(), delete_id.len());
+        assert_eq!(bytes, delete_id);
+    }
+    #[test]
+    fn new_id_skips_occupied_ids() {
+        let (_, mut client, _peer) = client();
+        let ids: Vec<Id> = (0..4).map(|_| {
+            let (id, _) = client.new_object(probe, "probe", 1, ()).unwrap();
+            id
+        }).collect();
+        assert_eq!(ids, (0..4).map(|i| Id::new(SERVER_ID_MIN + i)).collect::<Vec<_>>());
+        // Once the allocator wraps, the freed ID is the first free slot
+        client.remove(ids[1]).unwrap();
+        client.new_id = SERVER_ID_MIN;
+        assert_eq!(client.new_id().unwrap(), ids[1]);
+        client.new_id = u32::MAX;
+        assert_eq!(client.new_id().unwrap(), Id::new(u32::MAX));
+        assert_eq!(client.new_id().unwrap(), ids[1]);
+    }
+    #[test]
+    fn objects() {
+        let (_, mut client, _peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(3), probe, "three", 3, ()).into_any()).unwrap();
+        client.insert(crate::lease::Resident::new(Id::new(2), probe, "two", 2, ()).into_any()).unwrap();
+        // Leased objects are listed too
+        let _lease = client.lease(Id::new(3)).unwrap();
+        let mut objects: Vec<_> = client.objects().collect();
+        objects.sort_unstable_by_key(|&(id, _, _)| Into::<u32>::into(id));
+        assert_eq!(objects, [(Id::DISPLAY, "probe", 1), (Id::new(2), "two", 2), (Id::new(3), "three", 3)]);
+    }
+    #[test]
+    fn long_error_is_truncated() {
+        let (_, mut client, mut peer) = client();
+        let description = "é".repeat(wire::MAX_MESSAGE_SIZE);
+        client.error(&WlError { object: Id::new(2), error: 3, description: Cow::Owned(description) }).unwrap();
+        assert!(client.stream().sendmsg().unwrap().is_flushed());
+        let message = loop {
+            assert!(peer.recvmsg().unwrap());
+            if let Some(message) = peer.message() {
+                break message.unwrap()
+            }
+        };
+        assert!(message.size as usize <= wire::MAX_MESSAGE_SIZE);
+        assert_eq!(peer.object().unwrap(), Some(Id::new(2)));
+        assert_eq!(peer.u32().unwrap(), 3);
+        let description = peer.string().unwrap().unwrap();
+        assert!(description.ends_with("é…"));
+        peer.expect_end().unwrap();
+    }
+    #[test]
+    fn new_object() {
+        let (_, mut client, _peer) = client();
+        let (id, mut lease) = client.new_object(probe, "probe", 1, 5u32).unwrap();
+        assert!(Into::<u32>::into(id) >= SERVER_ID_MIN);
+        assert_eq!(lease.id(), id);
+        *lease += 1;
+        drop(lease);
+        assert_eq!(*client.get::<u32>(id).unwrap(), 6);
+    }
+    #[test]
+    fn global_filter_hides_globals() {
+        let (mut event_loop, mut client, mut peer) = client();
+        let globals = [
+            Global { interface: "probe", version: 1, constructor: probe_display },
+            Global { interface: "hidden", version: 1, constructor: probe_display },
+            Global { interface: "probe", version: 2, constructor: probe_display }
+        ];
+        client.set_global_filter(Box::new(|_, global| global.interface != "hidden"));
+        client.advertise_globals(Id::new(2), &globals).unwrap();
+        assert!(client.stream().sendmsg().unwrap().is_flushed());
+        assert!(peer.recvmsg().unwrap());
+        let advertised: Vec<_> = peer.messages().map(|message| message.unwrap().args).collect();
+        assert_eq!(advertised.len(), 2);
+        assert_eq!((advertised[0][0], advertised[1][0]), (0, 2));
+        let error = client.bind(&mut event_loop, 1, Some(globals[1]), &new_id(3, "hidden", 1)).unwrap_err();
+        assert_eq!(error.error, WlError::NO_GLOBAL.error);
+    }
+    #[test]
+    fn connect_hook_runs_before_dispatch() {
+        fn on_connect(event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>) {
+            // The display has already been created
+            event_loop.state.push(client.object_count() as u32)
+        }
+        let path = socket_path();
+        let mut server = Server::listen(&path, probe_display).unwrap();
+        server.set_connect_hook(on_connect);
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        event_loop.add(Box::new(server)).unwrap();
+        // The request is sent before the server has accepted the connection
+        let mut peer = Stream::connect(&path).unwrap();
+        send_words(&mut peer, &[1, header(3, 0), 42]);
+        while event_loop.state.len() < 2 {
+            event_loop.wait(1000).unwrap();
+        }
+        assert_eq!(event_loop.state, [1, 42]);
+    }
+    #[test]
+    fn failed_request_rolls_back_insert() {
+        /// Creates a probe with the ID given as its argument, then fails opcode 0.
+        fn factory(_: Lease<dyn Any>, _: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, message: Message) -> Result<(), DispatchError> {
+            let id = Id::new(client.stream().u32()?);
+            let guard = client.insert_guard(crate::lease::Resident::new(id, probe, "probe", 1, ()).into_any())?;
+            if message.opcode == 0 {
+                return Err(DispatchError::InvalidRequest { object: message.object, interface: "factory", opcode: 0 })
+            }
+            guard.commit();
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        client.set_dispatch_error_handler(Box::new(Recorder(errors.clone())));
+        client.insert(crate::lease::Resident::new(Id::new(2), factory, "factory", 1, ()).into_any()).unwrap();
+        send_words(&mut peer, &[2, header(3, 0), 3, 2, header(3, 1), 4]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(errors.borrow().len(), 1);
+        assert!(client.get_mut(Id::new(3)).is_none());
+        assert!(client.get_mut(Id::new(4)).is_some());
+    }
+    #[test]
+    fn destructors_run_on_remove() {
+        use std::cell::Cell;
+        fn count_destroyed(_: &mut Client<Vec<u32>>, lease: Lease<dyn Any>) -> Result<(), WlError<'static>> {
+            let count = lease.downcast::<Rc<Cell<u32>>>().unwrap();
+            count.set(count.get() + 1);
+            Ok(())
+        }
+        let (_, mut client, _peer) = client();
+        let log = Log::default();
+        let count = Rc::new(Cell::new(0u32));
+        client.insert(logged(2, &log)).unwrap();
+        client.insert(crate::lease::Resident::new(Id::new(3), probe, "counted", 1, count.clone()).with_destructor(count_destroyed).into_any()).unwrap();
+        client.remove(Id::new(3)).unwrap();
+        assert_eq!((log.borrow().len(), count.get()), (0, 1));
+        client.remove(Id::new(2)).unwrap();
+        assert_eq!((&log.borrow()[..], count.get()), (&[2][..], 1));
+        // Taking an object does not destroy it
+        client.insert(logged(4, &log)).unwrap();
+        drop(client.take(Id::new(4)).unwrap());
+        assert_eq!(*log.borrow(), [2]);
+    }
+    #[test]
+    fn events_from_a_constructor_during_dispatch() {
+        /// Advertises its formats to the client as it is created.
+        fn shm(_: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, id: Id, version: u32) -> Result<Resident<Vec<u32>>, WlError<'static>> {
+            for format in [0, 1] {
+                let key = client.stream().start_message(id, 0);
+                client.stream().send_u32(format)?;
+                client.stream().commit(key)?;
+            }
+            Ok(crate::lease::Resident::new(id, probe, "shm", version, ()).into_any())
+        }
+        fn registry(lease: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            let name = client.stream().u32()?;
+            let new_id = client.stream().new_id()?;
+            client.bind(event_loop, name, Some(Global { interface: "shm", version: 1, constructor: shm }), &new_id)?;
+            // The registry remains leased, and valid, throughout
+            assert!(client.lease(lease.id()).is_err());
+            assert_eq!(*lease.downcast::<u32>().unwrap(), 9);
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(2), registry, "registry", 1, 9u32).into_any()).unwrap();
+        let key = peer.start_message(Id::new(2), 0);
+        peer.send_u32(0).unwrap();
+        peer.send_new_id(&new_id(3, "shm", 1)).unwrap();
+        peer.commit(key).unwrap();
+        assert!(peer.sendmsg().unwrap().is_flushed());
+        client.input(&mut event_loop).unwrap();
+        assert!(client.get_mut(Id::new(3)).is_some());
+
+        assert!(peer.recvmsg().unwrap());
+        let events: Vec<_> = peer.messages().map(|event| event.unwrap()).map(|event| (event.object, event.args)).collect();
+        assert_eq!(events, [(Id::new(3), vec![0]), (Id::new(3), vec![1])]);
+    }
+    #[test]
+    fn pending_fds() {
+        fn take_fd(_: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            event_loop.state.push(client.pending_fds() as u32);
+            client.stream().file()?;
+            event_loop.state.push(client.pending_fds() as u32);
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(2), take_fd, "take_fd", 1, ()).into_any()).unwrap();
+        let key = peer.start_message(Id::new(2), 0);
+        peer.send_memfd(b"fd").unwrap();
+        peer.commit(key).unwrap();
+        assert!(peer.sendmsg().unwrap().is_flushed());
+        assert_eq!(client.pending_fds(), 0);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(event_loop.state, [1, 0]);
+        assert_eq!(client.pending_fds(), 0);
+    }
+    #[test]
+    fn replace_object() {
+        /// Adds each `uint` argument to a running total.
+        fn total(lease: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            let mut total = lease.downcast::<u32>().unwrap();
+            *total += client.stream().u32()?;
+            event_loop.state.push(*total);
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        let log = Log::default();
+        let id = Id::new(3);
+        client.insert(logged(3, &log)).unwrap();
+        let lease = client.lease(id).unwrap();
+        let error = client.replace(id, total, "total", 2, 10u32).unwrap_err();
+        assert_eq!(error.error, WlError::INTERNAL.error);
+        drop(lease);
+        client.replace(id, total, "total", 2, 10u32).unwrap();
+        let object = client.get_mut(id).unwrap();
+        assert_eq!((object.interface(), object.version()), ("total", 2));
+
+        send_words(&mut peer, &[3, header(3, 0), 5, 3, header(3, 0), 7]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(event_loop.state, [15, 22]);
+        // The old object is not destroyed, so neither its destructor runs nor is its ID deleted
+        assert!(log.borrow().is_empty());
+        let key = client.stream().start_message(Id::DISPLAY, 0);
+        client.stream().commit(key).unwrap();
+        assert_eq!(sent_bytes(client.stream(), &peer).len(), 8);
+    }
+    #[test]
+    fn client_limit() {
+        fn on_connect(event_loop: &mut EventLoop<Vec<u32>>, _: &mut Client<Vec<u32>>) {
+            event_loop.state.push(0)
+        }
+        fn on_reject(event_loop: &mut EventLoop<Vec<u32>>) {
+            event_loop.state.push(1)
+        }
+        let path = socket_path();
+        let mut server = Server::listen(&path, probe_display).unwrap();
+        server.set_connect_hook(on_connect);
+        server.set_reject_hook(on_reject);
+        server.set_max_clients(2);
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        event_loop.add(Box::new(server)).unwrap();
+        let connect = |event_loop: &mut EventLoop<Vec<u32>>| {
+            let peer = Stream::connect(&path).unwrap();
+            let len = event_loop.state.len();
+            while event_loop.state.len() == len {
+                event_loop.wait(1000).unwrap();
+            }
+            peer
+        };
+        let mut peers = [connect(&mut event_loop), connect(&mut event_loop)];
+        let rejected = connect(&mut event_loop);
+        assert_eq!(event_loop.state, [0, 0, 1]);
+        // The rejected connection is closed
+        assert_eq!(syslib::read(&rejected.socket, &mut [0; 4]).unwrap(), 0);
+        for (peer, value) in peers.iter_mut().zip([42, 43]) {
+            send_words(peer, &[1, header(3, 0), value]);
+            event_loop.wait(1000).unwrap();
+        }
+        assert_eq!(event_loop.state, [0, 0, 1, 42, 43]);
+
+        // Once a client disconnects another may take its place
+        let [first, _second] = peers;
+        drop(first);
+        event_loop.wait(1000).unwrap();
+        connect(&mut event_loop);
+        assert_eq!(event_loop.state, [0, 0, 1, 42, 43, 0]);
+    }
+    #[test]
+    fn event_to_another_client() {
+        /// Sends its `uint` argument to object 3 of the client it holds.
+        fn focus(lease: Lease<dyn Any>, event_loop: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            let target = *lease.downcast::<ClientId>().unwrap();
+            let value = client.stream().u32()?;
+            // The client being dispatched is not reachable through the event loop
+            assert!(matches!(event_loop.with_client(client.id(), |_| ()), Err(Error::NoClient)));
+            event_loop.with_client(target, |target| {
+                let key = target.stream().start_message(Id::new(3), 0);
+                target.stream().send_u32(value)?;
+                target.stream().commit(key)
+            }).unwrap()?;
+            Ok(())
+        }
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        let (stream, mut a_peer) = stream_pair();
+        let a = event_loop.add_client(stream, probe_display).unwrap();
+        let (stream, b_peer) = stream_pair();
+        let b = event_loop.add_client(stream, probe_display).unwrap();
+        event_loop.with_client(a, |client| client.insert(crate::lease::Resident::new(Id::new(3), focus, "focus", 1, b).into_any())).unwrap().unwrap();
+
+        send_words(&mut a_peer, &[3, header(3, 0), 42]);
+        event_loop.wait(1000).unwrap();
+        let mut bytes = [0; 12];
+        assert_eq!(syslib::read(&b_peer.socket, &mut bytes).unwrap(), 12);
+        assert_eq!(bytes.chunks(4).map(|word| u32::from_ne_bytes(word.try_into().unwrap())).collect::<Vec<_>>(), [3, header(3, 0), 42]);
+    }
+    #[test]
+    fn utf8_error_reaches_handler() {
+        fn named(_: Lease<dyn Any>, _: &mut EventLoop<Vec<u32>>, client: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            client.stream().string()?;
+            Ok(())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        client.set_dispatch_error_handler(Box::new(Recorder(errors.clone())));
+        client.insert(crate::lease::Resident::new(Id::new(3), named, "named", 1, ()).into_any()).unwrap();
+        send_words(&mut peer, &[3, header(5, 0), 5, u32::from_ne_bytes([b'a', b'b', 0xC0, b'c']), 0]);
+        client.input(&mut event_loop).unwrap();
+        assert_eq!(*errors.borrow(), ["Utf8Error(Utf8Error { valid_up_to: 2, error_len: Some(1) })"]);
+    }
+    #[test]
+    fn old_objects() {
+        use std::cell::Cell;
+        thread_local! {
+            static OVER_LIMIT: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+            static NOW: Cell<Instant> = Cell::new(Instant::now());
+        }
+        fn over_limit(_: &mut Client<Vec<u32>>, count: usize) {
+            OVER_LIMIT.with(|over_limit| over_limit.borrow_mut().push(count))
+        }
+        fn clock() -> Instant {
+            NOW.with(Cell::get)
+        }
+        let (_, mut client, _peer) = client();
+        client.set_object_limit(3, over_limit);
+        // The display was inserted before the clock was set, and so before any time it gives
+        client.set_clock(clock);
+        let log = Log::default();
+        client.insert(logged(3, &log)).unwrap();
+        client.insert(logged(4, &log)).unwrap();
+        NOW.with(|now| now.set(now.get() + Duration::from_secs(60)));
+        client.insert(logged(5, &log)).unwrap();
+        client.insert(logged(6, &log)).unwrap();
+        // The hook is only called as the limit is crossed
+        assert_eq!(OVER_LIMIT.with(|over_limit| over_limit.take()), [4]);
+
+        let mut old: Vec<u32> = client.objects_older_than(Duration::from_secs(30)).map(Into::into).collect();
+        old.sort();
+        assert_eq!(old, [1, 3, 4]);
+        assert_eq!(client.objects_older_than(Duration::from_secs(3600)).count(), 0);
+        // Reporting old objects does not destroy them
+        assert!(log.borrow().is_empty());
+    }
+    #[test]
+    fn client_from_inherited_socket() {
+        let (mut peer, inherited) = stream_pair();
+        // Hand the socket over as a bare file descriptor, as if inherited through `WAYLAND_SOCKET`
+        let fd = inherited.socket.fd().extend();
+        std::mem::forget(inherited);
+        let stream = unsafe { Stream::from_fd(fd) }.unwrap();
+        let flags: syslib::open::Flags = syslib::fcntl(&stream.socket, syslib::Fcntl::GetFd).unwrap().try_into().unwrap();
+        assert!(flags.any(syslib::open::Flags::CLOSE_ON_EXEC));
+
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        event_loop.add_client(stream, probe_display).unwrap();
+        send_words(&mut peer, &[1, header(3, 0), 42]);
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.state, [42]);
+    }
+    #[test]
+    fn socket_path_and_display_name() {
+        let path = socket_path();
+        let server = Server::listen(&path, probe_display).unwrap();
+        assert_eq!(server.socket_path(), Some(path.as_path()));
+        assert_eq!(server.display_name(), path.file_name());
+        drop(server);
+
+        let event_loop = Server::event_loop(&path, Vec::new(), probe_display).unwrap();
+        assert_eq!(event_loop.socket_path(), Some(path.as_path()));
+        assert_eq!(event_loop.display_name(), path.file_name());
+        // An event loop without a server has no socket
+        assert_eq!(EventLoop::new(()).unwrap().display_name(), None);
+    }
+    #[test]
+    fn source_count_drops_on_disconnect() {
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        assert!(event_loop.is_empty());
+        let (stream, first) = stream_pair();
+        event_loop.add_client(stream, probe_display).unwrap();
+        let (stream, second) = stream_pair();
+        event_loop.add_client(stream, probe_display).unwrap();
+        assert_eq!(event_loop.source_count(), 2);
+
+        drop(first);
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.source_count(), 1);
+        drop(second);
+        event_loop.wait(1000).unwrap();
+        assert!(event_loop.is_empty());
+    }
+    #[test]
+    fn object_args() {
+        let (_, mut client, mut peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(3), Child::dispatch, "child", 1, Child).into_any()).unwrap();
+        send_words(&mut peer, &[1, header(7, 0), 0, 0, 3, 3, 4]);
+        assert!(client.stream().recvmsg().unwrap());
+        client.stream().message().unwrap().unwrap();
+
+        let null = client.stream().object().unwrap();
+        assert!(matches!(client.object_arg::<Child>(null), Err(DispatchError::ObjectNull)));
+        let null = client.stream().object().unwrap();
+        assert!(client.nullable_object_arg::<Child>(null).unwrap().is_none());
+        let id = client.stream().object().unwrap();
+        assert_eq!(client.nullable_object_arg::<Child>(id).unwrap().unwrap().id(), Id::new(3));
+        let id = client.stream().object().unwrap();
+        assert_eq!(client.object_arg::<Child>(id).unwrap().id(), Id::new(3));
+        let id = client.stream().object().unwrap();
+        assert!(matches!(client.nullable_object_arg::<Child>(id), Err(DispatchError::ObjectNonExistent(id)) if id == Id::new(4)));
+        assert!(matches!(client.object_arg::<Child>(Some(Id::DISPLAY)), Err(DispatchError::UnexpectedObjectType { expected: "child", received: "probe", .. })));
+    }
+    #[test]
+    fn partial_send_waits_for_output() {
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        let (stream, peer) = stream_pair();
+        let id = event_loop.add_client(stream, probe_display).unwrap();
+        // More events than the socket buffer holds, so only part can be sent before the peer reads
+        let events = 1000;
+        event_loop.with_client(id, |client| for i in 0..events {
+            let key = client.stream().start_message(Id::DISPLAY, 0);
+            client.stream().send_bytes(&[i as u8; 1000]).unwrap();
+            client.stream().commit(key).unwrap();
+        }).unwrap();
+        let expected = events * (2 + 1 + 1000 / 4) * size_of::<u32>();
+
+        // The rest is sent as the socket becomes writable, without the client being sent to directly
+        let mut bytes = vec![0; expected];
+        let mut received = 0;
+        let mut waits = 0;
+        loop {
+            received += syslib::read(&peer.socket, &mut bytes).unwrap();
+            if received == expected {
+                break
+            }
+            event_loop.wait(1000).unwrap();
+            waits += 1;
+        }
+        assert!(waits > 0);
+        assert!(event_loop.client(id).unwrap().stream().sendmsg().unwrap().is_flushed());
+    }
+    #[test]
+    fn dispatch_error_context() {
+        fn failing(_: Lease<dyn Any>, _: &mut EventLoop<Vec<u32>>, _: &mut Client<Vec<u32>>, _: Message) -> Result<(), DispatchError> {
+            Err(WlError::DOMAIN.into())
+        }
+        let (mut event_loop, mut client, mut peer) = client();
+        client.insert(crate::lease::Resident::new(Id::new(3), failing, "failing", 1, ()).into_any()).unwrap();
+        send_words(&mut peer, &[3, header(2, 2)]);
+        let error = client.input(&mut event_loop).unwrap_err();
+        assert!(matches!(error, Error::Dispatch { interface: "failing", opcode: 2, .. }));
+        assert_eq!(error.protocol_error().map(|error| error.error), Some(WlError::DOMAIN.error));
+    }
+}
@@ -1,8 +1,20 @@
-use std::{fmt::{Debug, self}, path::Path, ops::{Deref, DerefMut}, borrow::Cow, mem::size_of, num::NonZeroU32};
+use std::{any::Any, fmt::{Debug, self}, path::{Path, PathBuf}, ffi::OsStr, net::SocketAddr, ops::{Deref, DerefMut, Add, Sub, Mul, Neg}, cmp::Ordering, borrow::Cow, mem::size_of, num::NonZeroU32, sync::Arc, collections::VecDeque, time::Duration};
 
 use crate::{prelude::*};
 use ahash::{HashMap, HashMapExt};
-use syslib::{Socket, File, FileDescriptor};
+use syslib::{Socket, File};
+
+/// Error codes of `wl_display.error`, used when the error is not specific to an interface.
+pub mod display_error {
+    /// The server couldn't find the object.
+    pub const INVALID_OBJECT: u32 = 0;
+    /// A method doesn't exist on the specified interface, or was malformed.
+    pub const INVALID_METHOD: u32 = 1;
+    /// The server is out of memory.
+    pub const NO_MEMORY: u32 = 2;
+    /// An implementation error occurred in the compositor.
+    pub const IMPLEMENTATION: u32 = 3;
+}
 
 #[derive(Debug)]
 pub struct WlError<'a> {
@@ -13,62 +25,82 @@ pub struct WlError<'a> {
 impl<'a> WlError<'a> {
     pub const NO_OBJECT: Self = Self {
         object: Id::DISPLAY,
-        error: 0,
+        error: display_error::INVALID_OBJECT,
         description: Cow::Borrowed("No object with that ID.")
     };
+    pub const NULL_OBJECT: Self = Self {
+        object: Id::DISPLAY,
+        error: display_error::INVALID_OBJECT,
+        description: Cow::Borrowed("Requests cannot be sent to the null object.")
+    };
     pub const CORRUPT: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Protocol violation or malformed request.")
     };
     pub const UNSUPPORTED_VERSION: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("The requested version of an interface is unsupported.")
     };
     pub const INVALID_OPCODE: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Request contains an invalid opcode.")
     };
     pub const NO_GLOBAL: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_OBJECT,
         description: Cow::Borrowed("No global with that name.")
     };
     pub const UTF_8: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Strings must be valid UTF-8.")
     };
+    pub const SERVER_ID: Self = Self {
+        object: Id::DISPLAY,
+        error: display_error::INVALID_OBJECT,
+        description: Cow::Borrowed("Clients cannot create objects with IDs reserved for the server.")
+    };
     pub const NON_NULLABLE: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Argument is not nullable.")
     };
     pub const NO_FD: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Expected a file descriptor but none were received.")
     };
     pub const DOMAIN: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("An argument was outside the range of allowed values.")
     };
     pub const LEAK: Self = Self {
         object: Id::DISPLAY,
-        error: 1,
+        error: display_error::INVALID_METHOD,
         description: Cow::Borrowed("Attempted to destroy an object before its children.")
     };
+    pub const FD_OVERFLOW: Self = Self {
+        object: Id::DISPLAY,
+        error: display_error::NO_MEMORY,
+        description: Cow::Borrowed("Too many file descriptors were sent without being used by a request.")
+    };
+    pub const NO_FD_TRANSPORT: Self = Self {
+        object: Id::DISPLAY,
+        error: display_error::IMPLEMENTATION,
+        description: Cow::Borrowed("File descriptors cannot be passed over this connection.")
+    };
     pub const OOM: Self = Self {
         object: Id::DISPLAY,
-        error: 2,
+        error: display_error::NO_MEMORY,
         description: Cow::Borrowed("The compositor is out of memory.")
     };
     pub const INTERNAL: Self = Self {
         object: Id::DISPLAY,
-        error: 3,
+        error: display_error::IMPLEMENTATION,
         description: Cow::Borrowed("Internal compositor state is corrupted.")
     };
 }
@@ -78,7 +110,7 @@ impl<'a> WlError<'a> {
 pub struct Id(NonZeroU32);
 impl Id {
     /// The display object that must always exist for Wayland to operate.
-    pub const DISPLAY: Self = Self(unsafe { NonZeroU32::new_unchecked(1) });
+    pub const DISPLAY: Self = Self::new(1);
     /// Create an ID from an integer.
     /// 
     /// # Panics
@@ -95,9 +127,9 @@ impl From<NonZeroU32> for Id {
         Self(id)
     }
 }
-impl Into<u32> for Id {
-    fn into(self) -> u32 {
-        self.0.into()
+impl From<Id> for u32 {
+    fn from(id: Id) -> Self {
+        id.0.into()
     }
 }
 impl fmt::Display for Id {
@@ -110,7 +142,7 @@ impl fmt::Display for Id {
 pub struct NewId {
     id: Id,
     version: u32,
-    interface: String
+    interface: Cow<'static, str>
 }
 impl NewId {
     #[inline]
@@ -127,16 +159,134 @@ impl NewId {
     }
 }
 /// Fixed decimal number as specified by the Wayland wire format
+/// 
+/// Being a two's complement integer scaled by 256, each value has exactly one representation. Unlike floats there
+/// is no negative zero, so equality and hashing of the raw bits agree with equality of the values.
 // TODO: proper Debug / Display implementations
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct Fixed(u32);
 impl Fixed {
+    /// The largest representable value, just under 8388608.
+    pub const MAX: Self = Self(i32::MAX as u32);
+    /// The smallest representable value, -8388608.
+    pub const MIN: Self = Self(i32::MIN as u32);
     #[inline]
     fn from_raw(raw: u32) -> Self {
         Self(raw)
     }
+    /// Create a fixed value from its signed 24.8 representation, as sent on the wire.
+    #[inline]
+    pub const fn from_bits(bits: i32) -> Self {
+        Self(bits as u32)
+    }
+    /// Get the signed 24.8 representation of the value, as sent on the wire.
+    #[inline]
+    pub const fn to_bits(self) -> i32 {
+        self.0 as i32
+    }
+    /// Convert a float to the nearest fixed value.
+    /// 
+    /// Values outside of the representable range saturate to `Fixed::MIN` or `Fixed::MAX`, and NaN becomes 0.
+    #[inline]
+    pub fn from_f64(value: f64) -> Self {
+        // Float to integer casts saturate, and -0.0 becomes 0
+        Self((value * 256.0).round() as i32 as u32)
+    }
+    /// Convert a float to the nearest fixed value, or fail with `WlError::DOMAIN` if it is outside of the representable range.
+    pub fn try_from_f64(value: f64) -> Result<Self, WlError<'static>> {
+        let scaled = (value * 256.0).round();
+        if scaled >= i32::MIN as f64 && scaled <= i32::MAX as f64 {
+            Ok(Self(scaled as i32 as u32))
+        } else {
+            Err(WlError::DOMAIN)
+        }
+    }
+    /// Convert to a float. Every fixed value is exactly representable.
+    #[inline]
+    pub fn to_f64(self) -> f64 {
+        self.0 as i32 as f64 / 256.0
+    }
+}
+impl From<f64> for Fixed {
+    #[inline]
+    fn from(value: f64) -> Self {
+        Self::from_f64(value)
+    }
+}
+impl From<Fixed> for f64 {
+    #[inline]
+    fn from(value: Fixed) -> Self {
+        value.to_f64()
+    }
+}
+impl From<i32> for Fixed {
+    /// Integers outside of the representable range saturate to `Fixed::MIN` or `Fixed::MAX`.
+    #[inline]
+    fn from(value: i32) -> Self {
+        const LIMIT: i32 = 1 << 23;
+        if value >= LIMIT {
+            Self::MAX
+        } else {
+            Self((value.max(-LIMIT) << 8) as u32)
+        }
+    }
 }
+impl PartialOrd for Fixed {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Fixed {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.to_bits().cmp(&other.to_bits())
+    }
+}
+// Arithmetic is exact as it operates on the 24.8 representation. Values come from clients, so rather than
+// panicking or wrapping around on overflow, results saturate to `Fixed::MIN` or `Fixed::MAX`
+impl Add for Fixed {
+    type Output = Self;
+    #[inline]
+    fn add(self, rhs: Self) -> Self {
+        Self::from_bits(self.to_bits().saturating_add(rhs.to_bits()))
+    }
+}
+impl Sub for Fixed {
+    type Output = Self;
+    #[inline]
+    fn sub(self, rhs: Self) -> Self {
+        Self::from_bits(self.to_bits().saturating_sub(rhs.to_bits()))
+    }
+}
+impl Mul<i32> for Fixed {
+    type Output = Self;
+    #[inline]
+    fn mul(self, rhs: i32) -> Self {
+        Self::from_bits(self.to_bits().saturating_mul(rhs))
+    }
+}
+impl Neg for Fixed {
+    type Output = Self;
+    /// Negating `Fixed::MIN` gives `Fixed::MAX`, as its negation is not representable.
+    #[inline]
+    fn neg(self) -> Self {
+        Self::from_bits(self.to_bits().saturating_neg())
+    }
+}
+impl From<Fixed> for i32 {
+    /// Discards the fractional part, rounding towards zero as `wl_fixed_to_int` does.
+    #[inline]
+    fn from(value: Fixed) -> Self {
+        value.0 as i32 / 256
+    }
+}
+
+/// The largest message, in bytes, that can be described by the 16-bit size field of a message header.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize & !0b11;
+/// The most file descriptors sent with a single call to `sendmsg`.
+const MAX_FDS_OUT: usize = 8;
 
 #[derive(Debug)]
 pub struct Message {
@@ -144,6 +294,43 @@ pub struct Message {
     pub opcode: u16,
     pub size: u16
 }
+/// A message with its arguments copied out of the receive buffer.
+/// 
+/// File descriptors are not delimited by message boundaries on the wire, so cannot be attributed to a message
+/// without knowing its signature. They remain queued on the `Stream` in the order they were received, and only
+/// their number is recorded.
+#[derive(Debug, Clone)]
+pub struct OwnedMessage {
+    pub object: Id,
+    pub opcode: u16,
+    /// The raw argument words, excluding the message header.
+    pub args: Vec<u32>,
+    /// The number of file descriptors queued on the stream as the message was read. These belong to this
+    /// message and those after it.
+    pub pending_fds: usize
+}
+/// An iterator over the complete messages in the receive buffer of a `Stream`.
+pub struct Messages<'a> {
+    stream: &'a mut Stream
+}
+impl<'a> Iterator for Messages<'a> {
+    type Item = Result<OwnedMessage, DispatchError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let message = match self.stream.message()? {
+            Ok(message) => message,
+            Err(e) => return Some(Err(e))
+        };
+        let pending_fds = self.stream.pending_rx_fds();
+        let words = message.size as usize / size_of::<u32>() - 2;
+        let args = (0..words).map(|_| self.stream.u32()).collect::<Result<_, _>>();
+        Some(args.map_err(Into::into).map(|args| OwnedMessage {
+            object: message.object,
+            opcode: message.opcode,
+            args,
+            pending_fds
+        }))
+    }
+}
 /// Used to complete a message, preventing new arguments from being pushed.
 #[must_use]
 #[derive(Debug)]
@@ -153,10 +340,44 @@ pub trait EventSource<T> {
     fn fd(&self) -> Fd<'static>;
     fn destroy(&mut self, _event_loop: &mut EventLoop<T>) {}
     fn input(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()>;
+    /// Allows the event source to be downcast when retrieved from the `EventLoop`.
+    fn as_any_mut(&mut self) -> Option<&mut (dyn Any + 'static)> {
+        None
+    }
+    /// Called when the file descriptor becomes writable, if the source has requested it with `EventLoop::watch_output`.
+    fn output(&mut self, _event_loop: &mut EventLoop<T>) -> crate::Result<()> {
+        Ok(())
+    }
+}
+/// Identifies an event source attached to an `EventLoop`.
+/// 
+/// File descriptors are reused once closed, so a token also records the generation of the source using the file
+/// descriptor. A token for a source that has since been removed will not refer to a new source reusing the file descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceToken {
+    fd: u32,
+    generation: u32
+}
+impl SourceToken {
+    #[inline]
+    pub fn fd(&self) -> u32 {
+        self.fd
+    }
 }
 pub struct EventLoop<T> {
     epoll: File,
     sources: HashMap<u32, Option<Box<dyn EventSource<T>>>>,
+    /// The generation of each file descriptor, incremented each time a source using it is added or removed.
+    generations: HashMap<u32, u32>,
+    /// Event sources that are removed along with their parent.
+    children: HashMap<SourceToken, Vec<SourceToken>>,
+    /// The parent of each child source, so that a child removed on its own can be forgotten by its parent.
+    parents: HashMap<SourceToken, SourceToken>,
+    /// The file descriptor of each source and the events it is registered for, not including output watched with
+    /// `watch_output`.
+    interest: HashMap<u32, (Fd<'static>, syslib::epoll::Events)>,
+    /// The socket of the server the event loop was created for, if any.
+    pub(crate) socket_path: Option<PathBuf>,
     pub state: T
 }
 impl<T> EventLoop<T> {
@@ -164,18 +385,101 @@ impl<T> EventLoop<T> {
         Ok(Self {
             epoll: syslib::epoll_create(syslib::epoll::Flags::CLOSE_ON_EXEC)?,
             sources: HashMap::new(),
+            generations: HashMap::new(),
+            children: HashMap::new(),
+            parents: HashMap::new(),
+            interest: HashMap::new(),
+            socket_path: None,
             state
         })
     }
-    pub fn add(&mut self, event_source: Box<dyn EventSource<T>>) -> crate::Result<()> {
+    /// The events sources are registered for by `add`, in level-triggered mode.
+    pub const DEFAULT_EVENTS: syslib::epoll::Events = syslib::epoll::Events::INPUT
+        .union(syslib::epoll::Events::ERROR)
+        .union(syslib::epoll::Events::HANG_UP);
+    pub fn add(&mut self, event_source: Box<dyn EventSource<T>>) -> crate::Result<SourceToken> {
+        self.add_with(event_source, Self::DEFAULT_EVENTS)
+    }
+    /// Add an event source that is notified of the given events, such as to use edge-triggered mode.
+    pub fn add_with(&mut self, event_source: Box<dyn EventSource<T>>, events: syslib::epoll::Events) -> crate::Result<SourceToken> {
         use syslib::epoll;
         let fd = event_source.fd();
         let event = epoll::Event {
-            events: epoll::Events::INPUT | epoll::Events::ERROR | epoll::Events::HANG_UP,
+            events,
             data: epoll::Data { fd }
         };
         syslib::epoll_ctl(&self.epoll, &fd, epoll::Cntl::Add(event))?;
+        self.interest.insert(fd.raw(), (fd, events));
         self.sources.insert(fd.raw(), Some(event_source));
+        let generation = self.generations.entry(fd.raw()).or_insert(0);
+        *generation = generation.wrapping_add(1);
+        Ok(SourceToken { fd: fd.raw(), generation: *generation })
+    }
+    /// Add an event source that is removed along with `parent`, which must be attached.
+    pub(crate) fn add_child(&mut self, parent: SourceToken, event_source: Box<dyn EventSource<T>>) -> crate::Result<SourceToken> {
+        let token = self.add(event_source)?;
+        self.children.entry(parent).or_default().push(token);
+        self.parents.insert(token, parent);
+        Ok(token)
+    }
+    /// Get the token that the next event source added with the file descriptor will be identified by.
+    pub fn token_for(&self, fd: Fd) -> SourceToken {
+        let generation = self.generations.get(&fd.raw()).copied().unwrap_or(0);
+        SourceToken { fd: fd.raw(), generation: generation.wrapping_add(1) }
+    }
+    /// The number of event sources attached, including any being dispatched.
+    #[inline]
+    pub fn source_count(&self) -> usize {
+        self.sources.len()
+    }
+    /// Returns true if no event sources are attached, such as once every client has disconnected from a server
+    /// that has itself been removed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+    /// Returns true if the token refers to an event source that is still attached.
+    pub fn contains(&self, token: SourceToken) -> bool {
+        self.sources.contains_key(&token.fd) && self.generations.get(&token.fd) == Some(&token.generation)
+    }
+    /// Get the event source identified by the token.
+    /// 
+    /// Returns `None` if the event source has been removed, or if it is the event source currently being dispatched.
+    pub fn source_mut(&mut self, token: SourceToken) -> Option<&mut (dyn EventSource<T> + 'static)> {
+        if !self.contains(token) {
+            return None
+        }
+        self.sources.get_mut(&token.fd)?.as_deref_mut()
+    }
+    /// Change the events an event source is notified of.
+    /// 
+    /// Fails with `Error::NoSource` if the source has been removed, even if a new source reuses its file descriptor.
+    pub fn modify(&mut self, token: SourceToken, events: syslib::epoll::Events) -> crate::Result<()> {
+        if !self.contains(token) {
+            return Err(Error::NoSource)
+        }
+        let (fd, _) = self.interest[&token.fd];
+        self.register(fd, events)?;
+        self.interest.insert(token.fd, (fd, events));
+        Ok(())
+    }
+    /// Start or stop notifying the event source with the given file descriptor when it becomes writable.
+    /// 
+    /// Output is watched in addition to the events the source was registered for.
+    pub fn watch_output(&mut self, fd: Fd<'static>, watch: bool) -> crate::Result<()> {
+        let mut events = self.interest.get(&fd.raw()).map_or(Self::DEFAULT_EVENTS, |&(_, events)| events);
+        if watch {
+            events = events | syslib::epoll::Events::OUTPUT;
+        }
+        self.register(fd, events)
+    }
+    fn register(&mut self, fd: Fd<'static>, events: syslib::epoll::Events) -> crate::Result<()> {
+        use syslib::epoll;
+        let event = epoll::Event {
+            events,
+            data: epoll::Data { fd }
+        };
+        syslib::epoll_ctl(&self.epoll, &fd, epoll::Cntl::Modify(event))?;
         Ok(())
     }
     pub fn wait(&mut self, timeout: u32) -> crate::Result<()> {
@@ -185,28 +489,81 @@ impl<T> EventLoop<T> {
         for event in events {
             let fd = unsafe { event.data.fd };
             let mut had_error = false;
-            if event.events.any(epoll::Events::INPUT) {
+            let readable = event.events.any(epoll::Events::INPUT);
+            let writable = event.events.any(epoll::Events::OUTPUT);
+            if readable || writable {
+                // An earlier event may have removed the source, such as when removing its parent
+                let Some(entry) = self.sources.get_mut(&fd.raw()) else {
+                    continue
+                };
                 // Lease the event source so that it can modify its owning data structure
-                let mut source = self.sources.get_mut(&fd.raw()).unwrap().take();
-                if let Err(err) = source.as_mut().unwrap().input(self) {
+                let mut source = entry.take();
+                let leased = source.as_mut().unwrap();
+                let result = if readable { leased.input(self) } else { Ok(()) }
+                    .and_then(|_| if writable { leased.output(self) } else { Ok(()) });
+                if let Err(err) = result {
                     #[cfg(debug_assertions)]
                     eprintln!("Dropping event {:?}: {:?}", fd, err);
                     had_error = true;
                 }
-                let leased_source = self.sources.get_mut(&fd.raw())
-                    .expect("An event source erroneously removed it's own entry.");
+                let Some(leased_source) = self.sources.get_mut(&fd.raw()) else {
+                    // The source removed itself with `EventLoop::remove`, and is dropped
+                    continue
+                };
                 // Return the lease of the event source
                 std::mem::swap(&mut source, leased_source)
             }
             if event.events.any(epoll::Events::ERROR | epoll::Events::HANG_UP) || had_error {
-                syslib::epoll_ctl(&self.epoll, &fd, epoll::Cntl::Delete)?;
-                let source = self.sources.remove(&fd.raw());
-                source.unwrap().unwrap().destroy(self);
+                self.remove_source(fd);
             }
         }
         Ok(())
     }
+    /// Detach an event source, returning it without calling `EventSource::destroy`.
+    /// 
+    /// Any children added for the source are removed and destroyed. If the source is the one currently being
+    /// dispatched, it is dropped once it returns and `None` is returned. Fails with `Error::NoSource` if the source
+    /// has already been removed, even if a new source reuses its file descriptor.
+    pub fn remove(&mut self, token: SourceToken) -> crate::Result<Option<Box<dyn EventSource<T>>>> {
+        if !self.contains(token) {
+            return Err(Error::NoSource)
+        }
+        let (fd, _) = self.interest[&token.fd];
+        Ok(self.remove_fd(fd))
+    }
+    /// Detach whichever event source is using the file descriptor, as `remove` does.
+    pub(crate) fn remove_fd(&mut self, fd: Fd<'static>) -> Option<Box<dyn EventSource<T>>> {
+        let source = self.sources.remove(&fd.raw())?;
+        // The file descriptor may have been closed already, which also removes it from the epoll set
+        let _ = syslib::epoll_ctl(&self.epoll, &fd, syslib::epoll::Cntl::Delete);
+        self.interest.remove(&fd.raw());
+        let generation = self.generations.entry(fd.raw()).or_insert(0);
+        let token = SourceToken { fd: fd.raw(), generation: *generation };
+        *generation = generation.wrapping_add(1);
+        if let Some(parent) = self.parents.remove(&token) {
+            if let Some(siblings) = self.children.get_mut(&parent) {
+                siblings.retain(|&child| child != token);
+                if siblings.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+        }
+        for child in self.children.remove(&token).unwrap_or_default() {
+            // A child may already have been removed on its own
+            if let Ok(Some(mut child)) = self.remove(child) {
+                child.destroy(self);
+            }
+        }
+        source
+    }
+    /// Detach and destroy an event source, along with any children added for it.
+    fn remove_source(&mut self, fd: Fd<'static>) {
+        if let Some(mut source) = self.remove_fd(fd) {
+            source.destroy(self);
+        }
+    }
 }
+
 impl<T> Deref for EventLoop<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -219,30 +576,276 @@ impl<T> DerefMut for EventLoop<T> {
     }
 }
 
+/// Runs on the thread driving the `EventLoop` each time a `Notifier` is woken.
+pub type NotifyFn<T> = Box<dyn FnMut(&mut EventLoop<T>)>;
+/// An event source that allows other threads to wake the `EventLoop`, running a callback on the thread driving it.
+pub struct Notifier<T> {
+    eventfd: Arc<File>,
+    callback: NotifyFn<T>
+}
+impl<T> Notifier<T> {
+    pub fn new<F: FnMut(&mut EventLoop<T>) + 'static>(callback: F) -> crate::Result<Self> {
+        use syslib::eventfd;
+        let eventfd = syslib::eventfd(0, eventfd::Flags::CLOSE_ON_EXEC | eventfd::Flags::NON_BLOCKING)?;
+        Ok(Self {
+            eventfd: Arc::new(eventfd),
+            callback: Box::new(callback)
+        })
+    }
+    /// Get a handle that can be sent to other threads to wake the event loop.
+    pub fn handle(&self) -> NotifyHandle {
+        NotifyHandle(self.eventfd.clone())
+    }
+}
+impl<T> EventSource<T> for Notifier<T> {
+    fn fd(&self) -> Fd<'static> {
+        self.eventfd.fd().extend()
+    }
+
+    fn input(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()> {
+        // Reading resets the counter, coalescing any notifications since the last wake
+        let mut count = [0; size_of::<u64>()];
+        syslib::read(&*self.eventfd, &mut count)?;
+        (self.callback)(event_loop);
+        Ok(())
+    }
+}
+/// A thread-safe handle used to wake the `EventLoop` owning a `Notifier`.
+#[derive(Clone)]
+pub struct NotifyHandle(Arc<File>);
+impl NotifyHandle {
+    /// Schedule the `Notifier` callback to run on the event loop thread.
+    pub fn notify(&self) -> crate::Result<()> {
+        syslib::write(&*self.0, &1u64.to_ne_bytes())?;
+        Ok(())
+    }
+}
+
+/// Runs each time a `Timer` fires, with the number of expirations since it last ran.
+pub type TimerFn<T> = Box<dyn FnMut(&mut EventLoop<T>, u64)>;
+/// An event source that runs a callback after a delay, optionally repeating.
+pub struct Timer<T> {
+    timerfd: File,
+    callback: TimerFn<T>
+}
+impl<T> Timer<T> {
+    /// Create a timer that runs `callback` once, after `delay`.
+    /// 
+    /// The timer stays attached to the event loop after it fires, until it is removed with `EventLoop::remove`.
+    pub fn once<F: FnMut(&mut EventLoop<T>, u64) + 'static>(delay: Duration, callback: F) -> crate::Result<Self> {
+        Self::new(delay, Duration::ZERO, callback)
+    }
+    /// Create a timer that runs `callback` every `interval`.
+    pub fn repeat<F: FnMut(&mut EventLoop<T>, u64) + 'static>(interval: Duration, callback: F) -> crate::Result<Self> {
+        Self::new(interval, interval, callback)
+    }
+    fn new<F: FnMut(&mut EventLoop<T>, u64) + 'static>(value: Duration, interval: Duration, callback: F) -> crate::Result<Self> {
+        use syslib::timerfd;
+        let timerfd = syslib::timerfd_create(timerfd::Clock::MONOTONIC, timerfd::Flags::CLOSE_ON_EXEC | timerfd::Flags::NON_BLOCKING)?;
+        // A zero value would disarm the timer instead of expiring immediately
+        let value = value.max(Duration::from_nanos(1));
+        syslib::timerfd_settime(&timerfd, timerfd::SetFlags::NONE, &timerfd::TimerSpec { interval, value })?;
+        Ok(Self {
+            timerfd,
+            callback: Box::new(callback)
+        })
+    }
+}
+impl<T> EventSource<T> for Timer<T> {
+    fn fd(&self) -> Fd<'static> {
+        self.timerfd.fd().extend()
+    }
+
+    /// Runs the callback with the number of times the timer has expired since it last ran, which is more than 1
+    /// if the event loop fell behind a repeating timer.
+    fn input(&mut self, event_loop: &mut EventLoop<T>) -> crate::Result<()> {
+        let mut expirations = [0; size_of::<u64>()];
+        syslib::read(&self.timerfd, &mut expirations)?;
+        (self.callback)(event_loop, u64::from_ne_bytes(expirations));
+        Ok(())
+    }
+}
+
+/// The kind of socket a `Server` or `Stream` communicates over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A Unix domain socket, able to carry file descriptors alongside messages.
+    Unix,
+    /// A TCP socket, for remote connections.
+    /// 
+    /// File descriptors cannot be sent over a TCP connection, so any request or event with an `fd` argument
+    /// will fail. Clients relying on `wl_shm` or `wl_keyboard.keymap` will not work over this transport.
+    Tcp
+}
+impl Transport {
+    /// Returns true if file descriptors can be passed using the transport.
+    #[inline]
+    pub fn passes_fds(self) -> bool {
+        self == Self::Unix
+    }
+}
+
 pub struct Server {
-    pub(crate) socket: Socket
+    pub(crate) socket: Socket,
+    pub(crate) transport: Transport,
+    /// The path of the Unix domain socket, if the server is listening on one.
+    path: Option<PathBuf>,
+    /// Exclusively locked for as long as the server owns a Unix domain socket.
+    _lock: Option<File>
 }
 impl Server {
+    /// Accept a pending connection.
+    /// 
+    /// The connected socket is non-blocking and closed on exec, with both flags set atomically.
+    pub fn accept(&self) -> crate::Result<Stream> {
+        use syslib::sock::TypeFlags;
+        let socket = syslib::accept4(&self.socket, TypeFlags::NON_BLOCKING | TypeFlags::CLOSE_ON_EXEC)?;
+        Stream::new(socket, self.transport)
+    }
+    /// Listen for connections on a Unix domain socket.
+    /// 
+    /// The file `<path>.lock` is created and exclusively locked while the server is alive. Once the lock is held, any
+    /// socket left at `path` by a server that has since exited is removed. If another server holds the lock,
+    /// this fails with `Error::SocketInUse`. Both the socket and the lock file are removed when the server is dropped.
     pub fn listen<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
         use std::os::unix::prelude::OsStrExt;
-        use syslib::sock::*;
+        use syslib::{sock::*, open};
+        let path = path.as_ref();
+        let lock = syslib::open(
+            &lock_path(path),
+            open::Flags::CREATE | open::Flags::READ_WRITE | open::Flags::CLOSE_ON_EXEC,
+            open::Mode::USER_READ | open::Mode::USER_WRITE | open::Mode::GROUP_READ | open::Mode::GROUP_WRITE
+        )?;
+        syslib::flock(&lock, syslib::Lock::EXCLUSIVE | syslib::Lock::NON_BLOCKING).map_err(|_| Error::SocketInUse)?;
+        // Holding the lock means that any existing socket is stale
+        let _ = std::fs::remove_file(path);
+
         let socket = syslib::socket(Domain::UNIX, Type::STREAM | TypeFlags::CLOSE_ON_EXEC, Protocol::UNSPECIFIED)?;
-        let address = UnixAddress::new(path.as_ref().as_os_str().as_bytes()).map_err(|_| Error::InvalidSocketPath)?;
+        let address = UnixAddress::new(path.as_os_str().as_bytes()).map_err(|_| Error::InvalidSocketPath)?;
         syslib::bind(&socket, address.address())?;
         syslib::listen(&socket, syslib::sock::MAX_CONNECTIONS)?;
 
         Ok(Self {
-            socket
+            socket,
+            transport: Transport::Unix,
+            path: Some(path.to_owned()),
+            _lock: Some(lock)
         })
     }
+    /// Listen for connections over TCP.
+    /// 
+    /// See `Transport::Tcp` for the limitations of this transport.
+    pub fn listen_tcp(address: SocketAddr) -> crate::Result<Self> {
+        use syslib::sock::*;
+        let domain = if address.is_ipv4() { Domain::INET } else { Domain::INET6 };
+        let socket = syslib::socket(domain, Type::STREAM | TypeFlags::CLOSE_ON_EXEC, Protocol::UNSPECIFIED)?;
+        let address = InetAddress::from(address);
+        syslib::bind(&socket, address.address())?;
+        syslib::listen(&socket, syslib::sock::MAX_CONNECTIONS)?;
+
+        Ok(Self {
+            socket,
+            transport: Transport::Tcp,
+            path: None,
+            _lock: None
+        })
+    }
+    #[inline]
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+    /// The path of the Unix domain socket the server is listening on, or `None` when listening over TCP.
+    #[inline]
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+    /// The file name of the socket, which clients should be given as `WAYLAND_DISPLAY`.
+    /// 
+    /// This is only sufficient when the socket is in `$XDG_RUNTIME_DIR`. Otherwise, the full path from
+    /// `socket_path` must be used.
+    pub fn display_name(&self) -> Option<&OsStr> {
+        self.path.as_deref()?.file_name()
+    }
+}
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The lock is still held, so no other server can have taken over the socket
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+            let _ = std::fs::remove_file(lock_path(path));
+        }
+    }
+}
+/// The path of the file locked by the server listening on the socket at `path`.
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    lock_path.into()
 }
 
 pub struct Stream {
     pub(crate) socket: Socket,
+    transport: Transport,
     rx_msg: RingBuffer<u32>,
+    /// The number of bytes of an incomplete word held in the first word of the spare capacity of `rx_msg`.
+    rx_partial: usize,
+    /// The number of argument words of the current message that have not been read.
+    rx_remaining: usize,
     tx_msg: Vec<u32>,
+    /// The number of words at the start of `tx_msg` that belong to committed messages.
+    tx_committed: usize,
+    /// The number of bytes at the start of `tx_msg` that have already been sent.
+    tx_offset: usize,
     rx_fd: RingBuffer<File>,
-    tx_fd: RingBuffer<Fd<'static>>,
+    tx_fd: RingBuffer<TxFd>,
+    /// File descriptors pushed for the message in progress, queued to be sent only once it is committed.
+    tx_fd_staged: Vec<TxFd>,
+    /// The byte offset in `tx_msg` and number of file descriptors of each committed message with unsent file
+    /// descriptors, oldest first.
+    tx_fd_messages: VecDeque<(usize, usize)>,
+    io_hook: Option<IoHook>,
+}
+/// Observes each `sendmsg` and `recvmsg` system call made by a `Stream`.
+pub type IoHook = Box<dyn FnMut(&IoEvent)>;
+/// A completed `sendmsg` or `recvmsg` system call.
+#[derive(Debug, Clone, Copy)]
+pub struct IoEvent {
+    /// True for `sendmsg`, false for `recvmsg`.
+    pub send: bool,
+    /// The number of bytes moved by the call.
+    pub bytes: usize,
+    /// The number of file descriptors moved by the call.
+    pub fds: usize,
+    /// The time spent in the system call.
+    pub elapsed: Duration
+}
+/// The outcome of `Stream::sendmsg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flush {
+    /// All committed messages have been sent.
+    Flushed,
+    /// The socket could not accept everything, leaving the given number of bytes to be sent.
+    Partial(usize)
+}
+impl Flush {
+    #[inline]
+    pub fn is_flushed(self) -> bool {
+        self == Self::Flushed
+    }
+}
+/// A file descriptor queued to be sent.
+enum TxFd {
+    Borrowed(Fd<'static>),
+    /// Closed once it has been sent or discarded.
+    Owned(File)
+}
+impl TxFd {
+    fn fd(&self) -> Fd<'static> {
+        match self {
+            Self::Borrowed(fd) => *fd,
+            Self::Owned(file) => file.fd().extend()
+        }
+    }
 }
 impl Stream {
     /// Open a new stream connected to a Unix domain socket.
@@ -255,105 +858,249 @@ impl Stream {
         let address = UnixAddress::new(path.as_ref().as_os_str().as_bytes()).map_err(|_| Error::InvalidSocketPath)?;
         syslib::connect(&socket, address.address())?;
 
-        Self::new(socket)
+        Self::new(socket, Transport::Unix)
     }
-    pub(crate) fn new(socket: Socket) -> crate::Result<Self> {
+    /// Open a new stream connected over TCP.
+    /// 
+    /// See `Transport::Tcp` for the limitations of this transport.
+    pub fn connect_tcp(address: SocketAddr) -> crate::Result<Self> {
+        use syslib::sock::*;
+        let domain = if address.is_ipv4() { Domain::INET } else { Domain::INET6 };
+        let socket = syslib::socket(domain, Type::STREAM | TypeFlags::CLOSE_ON_EXEC, Protocol::UNSPECIFIED)?;
+        let address = InetAddress::from(address);
+        syslib::connect(&socket, address.address())?;
+
+        Self::new(socket, Transport::Tcp)
+    }
+    /// Adopt an already connected Unix domain socket, such as one inherited through `WAYLAND_SOCKET`.
+    /// 
+    /// The socket is set to close on exec, as it is for streams the crate opens itself.
+    /// 
+    /// ## Safety
+    /// `fd` must be an open socket that is not owned elsewhere, as the stream takes ownership of it.
+    pub unsafe fn from_fd(fd: Fd<'static>) -> crate::Result<Self> {
+        let socket: Socket = fd.owned().into();
         let flags: syslib::open::Flags = syslib::fcntl(&socket, syslib::Fcntl::GetFd)?.try_into()?;
         syslib::fcntl(&socket, syslib::Fcntl::SetFd(flags | syslib::open::Flags::CLOSE_ON_EXEC))?;
+        Self::new(socket, Transport::Unix)
+    }
+    /// Create a stream from a connected socket, which must already have been opened with `CLOSE_ON_EXEC`.
+    pub(crate) fn new(socket: Socket, transport: Transport) -> crate::Result<Self> {
         Ok(Self {
             socket,
+            transport,
             rx_msg: RingBuffer::new(1024),
+            rx_partial: 0,
+            rx_remaining: 0,
             tx_msg: Vec::with_capacity(1024),
+            tx_committed: 0,
+            tx_offset: 0,
             rx_fd: RingBuffer::new(8),
-            tx_fd: RingBuffer::new(8)
+            tx_fd: RingBuffer::new(8),
+            tx_fd_staged: Vec::new(),
+            tx_fd_messages: VecDeque::new(),
+            io_hook: None
         })
     }
-    pub fn message(&mut self) -> Option<Result<Message, WlError<'static>>> {
+    /// Read the header of the next message, once the whole message has been received.
+    /// 
+    /// Any arguments of the previous message that were not read are discarded first, so that a request handler
+    /// that fails part way through its arguments does not leave the stream misaligned.
+    /// 
+    /// A message sent to the null object fails with `DispatchError::NullTarget`, and its arguments are skipped by the
+    /// next call. A header too small to be valid fails with `WlError::CORRUPT`, and as the start of the next message
+    /// can no longer be found, all received data is discarded.
+    pub fn message(&mut self) -> Option<Result<Message, DispatchError>> {
+        self.skip_remaining();
         let req = self.rx_msg.get(1)?;
         let size = ((req & 0xFFFF_0000) >> 16) as u16;
         if size < 8 {
-            return Some(Err(WlError::CORRUPT))
+            self.rx_msg.clear();
+            return Some(Err(DispatchError::Protocol(WlError::CORRUPT)))
         }
         if self.rx_msg.len() < (size as usize) / size_of::<u32>() {
             return None;
         }
         let opcode = (req & 0xFFFF) as u16;
-        let object = match NonZeroU32::new(self.rx_msg.pop().unwrap()).ok_or(WlError::NON_NULLABLE) {
-            Ok(object) => object,
-            Err(e) => return Some(Err(e))
-        };
-        let object = Id(object);
+        let object = NonZeroU32::new(self.rx_msg.pop().unwrap());
         let _ = self.rx_msg.pop();
-        Some(Ok(Message { object, opcode, size }))
+        self.rx_remaining = size as usize / size_of::<u32>() - 2;
+        match object {
+            Some(object) => Some(Ok(Message { object: Id(object), opcode, size })),
+            None => Some(Err(DispatchError::NullTarget))
+        }
+    }
+    /// Get the number of argument words of the current message that have not been read.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.rx_remaining
+    }
+    /// Discard the argument words of the current message that have not been read.
+    pub fn skip_remaining(&mut self) {
+        for _ in 0..self.rx_remaining {
+            self.rx_msg.pop();
+        }
+        self.rx_remaining = 0;
     }
+    /// Ensure all arguments of the current message have been read.
+    /// 
+    /// Leftover words indicate a malformed message, or one sent for a different version of the interface.
+    pub fn expect_end(&self) -> Result<(), WlError<'static>> {
+        if self.rx_remaining == 0 {
+            Ok(())
+        } else {
+            Err(WlError::CORRUPT)
+        }
+    }
+    /// Read the next argument word of the current message.
+    fn arg(&mut self) -> Result<u32, WlError<'static>> {
+        if self.rx_remaining == 0 {
+            return Err(WlError::CORRUPT)
+        }
+        let word = self.rx_msg.pop().ok_or(WlError::CORRUPT)?;
+        self.rx_remaining -= 1;
+        Ok(word)
+    }
+    /// Discard all buffered messages and file descriptors, leaving the socket open.
+    /// 
+    /// Received file descriptors are closed.
+    pub fn reset(&mut self) {
+        self.rx_msg.clear();
+        self.rx_partial = 0;
+        self.rx_remaining = 0;
+        self.tx_msg.clear();
+        self.tx_committed = 0;
+        self.tx_offset = 0;
+        self.rx_fd.clear();
+        self.tx_fd.clear();
+        self.tx_fd_staged.clear();
+        self.tx_fd_messages.clear();
+    }
+    /// Iterate over the complete messages in the receive buffer, copying out their arguments.
+    /// 
+    /// This is intended for inspecting or replaying messages and should not be interleaved with dispatch.
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages { stream: self }
+    }
+    /// Begin a new message.
+    /// 
+    /// Any message that was started but never committed is discarded, along with its file descriptors.
     pub fn start_message(&mut self, id: Id, opcode: u16) -> CommitKey {
+        self.rollback();
         let key = CommitKey(self.tx_msg.len());
         self.tx_msg.push(id.into());
         self.tx_msg.push(opcode as u32);
         key
     }
     /// Commits a message, ammending the message header to include the pushed arguments.
+    /// 
+    /// File descriptors pushed since the message was started are queued to be sent with it.
     pub fn commit(&mut self, key: CommitKey) -> Result<(), WlError<'static>> {
-        let len = self.tx_msg.len() - key.0;
-        let req = self.tx_msg.get_mut(key.0 + 1).expect("Invalid message commit key.");
-        *req = (*req & 0x0000_FFFF) | ((len as u32) << 18);
+        // The size is in bytes, but the message is made of whole words so it is always a multiple of 4
+        let len = self.tx_msg.len().saturating_sub(key.0);
+        let size = len * size_of::<u32>();
+        debug_assert!(key.0 == self.tx_committed && size >= 8, "Invalid message commit key.");
+        if key.0 != self.tx_committed || size < 8 {
+            return Err(WlError::INTERNAL)
+        }
+        if size > MAX_MESSAGE_SIZE {
+            self.rollback();
+            return Err(WlError::INTERNAL)
+        }
+        let req = &mut self.tx_msg[key.0 + 1];
+        *req = (*req & 0x0000_FFFF) | ((size as u32) << 16);
+        if !self.tx_fd_staged.is_empty() {
+            self.tx_fd_messages.push_back((key.0 * size_of::<u32>(), self.tx_fd_staged.len()));
+        }
+        for fd in self.tx_fd_staged.drain(..) {
+            // `send_file` ensures there is room for every staged file descriptor
+            let _ = self.tx_fd.push(fd);
+        }
+        self.tx_committed = self.tx_msg.len();
         Ok(())
     }
+    /// Abandon a message, discarding its arguments and file descriptors so they are not sent with the next message.
+    pub fn abort(&mut self, key: CommitKey) {
+        debug_assert_eq!(key.0, self.tx_committed, "Invalid message commit key.");
+        self.rollback();
+    }
+    /// Discard the uncommitted message, if any.
+    fn rollback(&mut self) {
+        if self.tx_offset == self.tx_committed * size_of::<u32>() {
+            // Everything committed has already been sent
+            self.tx_committed = 0;
+            self.tx_offset = 0;
+        }
+        self.tx_msg.truncate(self.tx_committed);
+        self.tx_fd_staged.clear();
+    }
     pub fn i32(&mut self) -> Result<i32, WlError<'static>> {
-        self.rx_msg.pop().map(|i| i as i32).ok_or(WlError::CORRUPT)
+        self.arg().map(|i| i as i32)
     }
     pub fn send_i32(&mut self, i32: i32) -> Result<(), WlError<'static>> {
         self.tx_msg.push(i32 as u32);
         Ok(())
     }
     pub fn u32(&mut self) -> Result<u32, WlError<'static>> {
-        self.rx_msg.pop().ok_or(WlError::CORRUPT)
+        self.arg()
     }
     pub fn send_u32(&mut self, u32: u32) -> Result<(), WlError<'static>> {
         self.tx_msg.push(u32);
         Ok(())
     }
+    /// Read an enum argument backed by a `uint`, failing with `DispatchError::NoVariant` if it is not a known value.
+    pub fn uint_enum<E: TryFrom<u32>>(&mut self) -> Result<E, DispatchError> {
+        let value = self.u32()?;
+        value.try_into().map_err(|_| DispatchError::NoVariant { name: std::any::type_name::<E>(), value })
+    }
+    /// Read an enum argument backed by an `int`, failing with `DispatchError::NoVariant` if it is not a known value.
+    /// 
+    /// The word is read as signed so that negative discriminants are preserved.
+    pub fn int_enum<E: TryFrom<i32>>(&mut self) -> Result<E, DispatchError> {
+        let value = self.i32()?;
+        value.try_into().map_err(|_| DispatchError::NoVariant { name: std::any::type_name::<E>(), value: value as u32 })
+    }
     pub fn fixed(&mut self) -> Result<Fixed, WlError<'static>> {
-        self.rx_msg.pop().map(|i| Fixed::from_raw(i)).ok_or(WlError::CORRUPT)
+        self.arg().map(Fixed::from_raw)
     }
     pub fn send_fixed(&mut self, fixed: Fixed) -> Result<(), WlError<'static>> {
         self.tx_msg.push(fixed.0);
         Ok(())
     }
+    /// Read a nullable string argument, failing with `DispatchError::Utf8Error` if it is not valid UTF-8.
     #[inline]
-    pub fn string(&mut self) -> Result<Option<String>, WlError<'static>> {
-        let mut bytes = match self.bytes() {
-            Ok(bytes) => bytes,
-            Err(e) => return Err(e)
-        };
+    pub fn string(&mut self) -> Result<Option<String>, DispatchError> {
+        let mut bytes = self.bytes()?;
+        // A null string has a length of 0, while an empty string still has a null terminator
+        if bytes.is_empty() {
+            return Ok(None)
+        }
         // Expect the string to be null-terminated
         let Some(0) = bytes.pop() else {
-            return Err(WlError::CORRUPT)
+            return Err(WlError::CORRUPT.into())
         };
-        if bytes.len() == 0 {
-            Ok(None)
-        } else {
-            String::from_utf8(bytes).map_err(|_| WlError::UTF_8).map(Some)
-        }
+        String::from_utf8(bytes)
+            .map_err(|error| DispatchError::Utf8Error(error.utf8_error()))
+            .map(Some)
     }
     #[inline]
     pub fn send_string(&mut self, string: Option<&str>) -> Result<(), WlError<'static>> {
         let Some(string) = string else {
             return self.send_u32(0)
         };
-        let len: u32 = string.len().try_into().unwrap();
-        let len = (len + 4) & !3;
+        // The length includes the null terminator
+        let len: u32 = (string.len() + 1).try_into().unwrap();
         self.send_u32(len)?;
-        self.tx_msg.reserve(len as usize);
-        unsafe {
-            self.tx_msg.as_mut_ptr().add(self.tx_msg.len() + (len as usize / size_of::<u32>()) - 1).write(0);
-            (self.tx_msg.as_mut_ptr().add(self.tx_msg.len()) as *mut u8).copy_from(string.as_ptr(), string.len());
-            self.tx_msg.set_len(self.tx_msg.len() + (len as usize / size_of::<u32>()));
-        }
+        self.send_padded(string.as_bytes(), len as usize);
         Ok(())
     }
+    /// Read a nullable object argument, where an ID of 0 is the null object.
     pub fn object(&mut self) -> Result<Option<Id>, WlError<'static>> {
-        self.rx_msg.pop().map(|i| NonZeroU32::new(i).map(Id)).ok_or(WlError::CORRUPT)
+        self.arg().map(|i| NonZeroU32::new(i).map(Id))
+    }
+    /// Read an object argument that is not allowed to be null, failing with `DispatchError::ObjectNull` if it is.
+    pub fn non_nullable_object(&mut self) -> Result<Id, DispatchError> {
+        self.object()?.ok_or(DispatchError::ObjectNull)
     }
     pub fn send_object(&mut self, object: Option<Id>) -> Result<(), WlError<'static>> {
         if let Some(object) = object {
@@ -362,11 +1109,18 @@ impl Stream {
             self.send_u32(0)
         }
     }
-    pub fn new_id(&mut self) -> Result<NewId, WlError<'static>> {
+    pub fn new_id(&mut self) -> Result<NewId, DispatchError> {
         let interface = self.string()?.ok_or(WlError::NON_NULLABLE)?;
         let version = self.u32()?;
-        let id = self.object()?.ok_or(WlError::NON_NULLABLE)?;
-        Ok(NewId { id, version, interface })
+        let id = self.non_nullable_object()?;
+        Ok(NewId { id, version, interface: Cow::Owned(interface) })
+    }
+    /// Read a `new_id` argument for which the protocol specifies the interface.
+    /// 
+    /// Only the ID is sent on the wire, with the interface and version implied by the request.
+    pub fn new_id_static(&mut self, interface: &'static str, version: u32) -> Result<NewId, DispatchError> {
+        let id = self.non_nullable_object()?;
+        Ok(NewId { id, version, interface: Cow::Borrowed(interface) })
     }
     pub fn send_new_id(&mut self, new_id: &NewId) -> Result<(), WlError<'static>> {
         self.send_string(Some(new_id.interface()))?;
@@ -378,7 +1132,7 @@ impl Stream {
         if len == 0 { return Ok(Vec::new()) }
         // divide by 4 rounding up
         let take_len = (len as usize >> 2) + (len & 0b11 != 0) as usize;
-        if self.rx_msg.len() < take_len {
+        if self.rx_remaining < take_len || self.rx_msg.len() < take_len {
             return Err(WlError::CORRUPT)
         }
         let mut bytes: Vec<u8> = Vec::with_capacity(len as usize);
@@ -393,97 +1147,239 @@ impl Stream {
             // Safety: The values in the range between `back` and `front` are initialised and any bit pattern is valid for u8
             unsafe {
                 let src = self.rx_msg.data.as_ptr() as *const u8;
-                let part_len = self.rx_msg.data.len() * size_of::<u32>() - self.rx_msg.back * size_of::<u32>();
+                let part_len = (self.rx_msg.data.len() * size_of::<u32>() - self.rx_msg.back * size_of::<u32>()).min(len as usize);
                 bytes.as_mut_ptr().copy_from_nonoverlapping(src.add(self.rx_msg.back * size_of::<u32>()), part_len);
-                bytes.as_mut_ptr().add(part_len).copy_from_nonoverlapping(src, self.rx_msg.front * size_of::<u32>());
+                bytes.as_mut_ptr().add(part_len).copy_from_nonoverlapping(src, len as usize - part_len);
                 bytes.set_len(len as usize);
             }
         }
         self.rx_msg.back = (self.rx_msg.back + take_len) & (self.rx_msg.data.len() - 1);
+        self.rx_remaining -= take_len;
         Ok(bytes)
     }
     pub fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), WlError<'static>> {
-        if bytes.len() == 0 {
-            return Ok(())
-        }
+        // The length is sent even for an empty array
         let len: u32 = bytes.len().try_into().unwrap();
-        let len = (len + 3) & !3;
         self.send_u32(len)?;
-        self.tx_msg.reserve(len as usize);
+        self.send_padded(bytes, len as usize);
+        Ok(())
+    }
+    /// Push `bytes` followed by zeroes, filling `size` bytes rounded up to a whole number of words.
+    fn send_padded(&mut self, bytes: &[u8], size: usize) {
+        debug_assert!(bytes.len() <= size);
+        // divide by 4 rounding up
+        let words = (size + 0b11) / size_of::<u32>();
+        if words == 0 {
+            return
+        }
+        self.tx_msg.reserve(words);
+        // Safety: The space has been reserved, and zeroing the last word before copying initialises all of the padding
         unsafe {
-            self.tx_msg.as_mut_ptr().add(self.tx_msg.len() + (len as usize / size_of::<u32>()) - 1).write(0);
-            (self.tx_msg.as_mut_ptr().add(self.tx_msg.len()) as *mut u8).copy_from(bytes.as_ptr(), bytes.len());
-            self.tx_msg.set_len(self.tx_msg.len() + (len as usize / size_of::<u32>()));
+            let dst = self.tx_msg.as_mut_ptr().add(self.tx_msg.len());
+            dst.add(words - 1).write(0);
+            (dst as *mut u8).copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+            self.tx_msg.set_len(self.tx_msg.len() + words);
         }
-        Ok(())
     }
+    #[inline]
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+    /// The number of received file descriptors that have not yet been read.
+    #[inline]
+    pub fn pending_rx_fds(&self) -> usize {
+        self.rx_fd.len()
+    }
+    /// The number of file descriptors queued to be sent with committed messages.
+    #[inline]
+    pub fn pending_tx_fds(&self) -> usize {
+        self.tx_fd.len()
+    }
+    /// Take the next received file descriptor.
+    /// 
+    /// File descriptors are not tied to message boundaries, so they are returned in the order they were received.
+    /// This matches the order of `fd` arguments across consecutive requests as long as each request consumes
+    /// exactly its own file descriptors.
     pub fn file(&mut self) -> Result<File, WlError<'static>> {
-        self.rx_fd.pop().ok_or(WlError::CORRUPT)
+        if !self.transport.passes_fds() {
+            return Err(WlError::NO_FD_TRANSPORT)
+        }
+        self.rx_fd.pop().ok_or(WlError::NO_FD)
     }
+    /// Attach a file descriptor to the message in progress.
+    /// 
+    /// The file descriptor is only queued for sending once the message is committed.
     pub fn send_file(&mut self, fd: Fd<'static>) -> Result<(), WlError<'static>> {
-        if let Some(_) = self.tx_fd.push(fd) {
+        self.stage_fd(TxFd::Borrowed(fd))
+    }
+    /// Attach a file descriptor to the message in progress, handing ownership to the stream.
+    /// 
+    /// The file is closed once it has been sent, or if the message is aborted.
+    pub fn send_file_owned(&mut self, file: File) -> Result<(), WlError<'static>> {
+        self.stage_fd(TxFd::Owned(file))
+    }
+    fn stage_fd(&mut self, fd: TxFd) -> Result<(), WlError<'static>> {
+        if !self.transport.passes_fds() {
+            return Err(WlError::NO_FD_TRANSPORT)
+        }
+        // One slot of the ring buffer is always left empty
+        if self.tx_fd.len() + self.tx_fd_staged.len() + 1 >= self.tx_fd.capacity() {
             Err(WlError::INTERNAL)
         } else {
+            self.tx_fd_staged.push(fd);
             Ok(())
         }
     }
+    /// Copy `bytes` in to a sealed memfd and attach it to the message in progress.
+    /// 
+    /// Returns the size of the file, which usually accompanies the file descriptor as an argument.
+    /// This allows data larger than `MAX_MESSAGE_SIZE`, such as keymaps, to be shared with the peer.
+    pub fn send_memfd(&mut self, bytes: &[u8]) -> crate::Result<u32> {
+        use syslib::{memfd, Seals};
+        let size = u32::try_from(bytes.len()).map_err(|_| Error::Protocol(WlError::DOMAIN))?;
+        if !self.transport.passes_fds() {
+            return Err(Error::Protocol(WlError::NO_FD_TRANSPORT))
+        }
+        let file = syslib::memfd_create(c"yutani", memfd::Flags::CLOSE_ON_EXEC | memfd::Flags::ALLOW_SEALING)?;
+        let mut written = 0;
+        while written < bytes.len() {
+            written += syslib::write(&file, &bytes[written..])?;
+        }
+        syslib::fcntl(&file, syslib::Fcntl::AddSeals(Seals::SHRINK | Seals::GROW | Seals::WRITE | Seals::SEAL))?;
+        self.send_file_owned(file).map_err(Error::Protocol)?;
+        Ok(size)
+    }
 
+    /// Set a hook observing each `sendmsg` and `recvmsg` system call, such as to profile I/O.
+    /// 
+    /// The system calls are only timed while a hook is set.
+    pub fn set_io_hook(&mut self, hook: IoHook) {
+        self.io_hook = Some(hook)
+    }
+    /// Remove the I/O hook, if any.
+    pub fn take_io_hook(&mut self) -> Option<IoHook> {
+        self.io_hook.take()
+    }
     /// Read from a file descriptor in to the buffer.
     /// 
-    /// Returns true if any bytes were read. If the bytes read is not a multiple of `size_of::<u32>()`,
-    /// the extra bytes are discarded.
+    /// Returns true if any bytes were read, or false if there was nothing to read. Stream sockets may split messages at any byte, so if the bytes read
+    /// end part way through a word, the partial word is kept and completed by the next read.
     pub fn recvmsg(&mut self) -> crate::Result<bool> {
         use syslib::*;
-        let t = (self.rx_msg.front + self.rx_msg.data.len() - 1) & (self.rx_msg.data.len() - 1);
-        if self.rx_msg.front == t {
+        if self.rx_msg.is_full() {
+            // Grow to fit a message too large for the buffer, as it could otherwise never be read
+            let size = self.rx_msg.get(1).map_or(0, |req| (req >> 16) as usize / size_of::<u32>());
+            if size > self.rx_msg.len() {
+                self.rx_msg.grow()
+            }
+        }
+        let partial = self.rx_partial;
+        let (first, second) = self.rx_msg.spare_capacity_mut();
+        if first.is_empty() {
             return Ok(false)
         }
+        // The bytes of a partial word were left at the start of the spare capacity by the previous read
         let iov = unsafe {
-            if self.rx_msg.front > t {
-                [
-                    IoVecMut::maybe_uninit(self.rx_msg.data.as_mut_ptr().add(self.rx_msg.front) as *mut u8, (self.rx_msg.data.len() - self.rx_msg.front) * size_of::<u32>()),
-                    IoVecMut::maybe_uninit(self.rx_msg.data.as_mut_ptr() as *mut u8, t * size_of::<u32>())
-                ]
-            } else {
-                [
-                    IoVecMut::maybe_uninit(self.rx_msg.data.as_mut_ptr().add(self.rx_msg.front) as *mut u8, (t - self.rx_msg.front) * size_of::<u32>()),
-                    IoVecMut::maybe_uninit(std::ptr::null_mut(), 0)
-                ]
-            }
+            [
+                IoVecMut::maybe_uninit((first.as_mut_ptr() as *mut u8).add(partial), first.len() * size_of::<u32>() - partial),
+                IoVecMut::maybe_uninit(second.as_mut_ptr() as *mut u8, second.len() * size_of::<u32>())
+            ]
         };
         let mut ancillary = sock::Ancillary::<Fd, 8>::new();
-        let read = syslib::recvmsg(&self.socket, &iov, Some(&mut ancillary), syslib::sock::Flags::NONE)? / size_of::<u32>();
-        self.rx_msg.front = (self.rx_msg.front + read) & (self.rx_msg.data.len() - 1);
+        let start = self.io_hook.is_some().then(std::time::Instant::now);
+        let bytes = loop {
+            match syslib::recvmsg(&self.socket, &iov, Some(&mut ancillary), sock::Flags::NONE) {
+                Ok(bytes) => break bytes,
+                Err(e) if e == Error::INTR => continue,
+                // A spurious wake up of a non-blocking socket is not an error
+                Err(e) if e == Error::AGAIN => return Ok(false),
+                Err(e) => return Err(e.into())
+            }
+        };
+        let read = (partial + bytes) / size_of::<u32>();
+        // Safety: The kernel has initialised the words that were read in to the spare capacity
+        unsafe { self.rx_msg.advance_front(read) };
+        // Any remaining bytes are now at the start of the spare capacity
+        self.rx_partial = (partial + bytes) % size_of::<u32>();
+        let mut fds = 0;
+        let mut overflowed = false;
         if ancillary.ty() == sock::AncillaryType::RIGHTS && ancillary.level() == sock::Level::SOCKET {
             for fd in ancillary.items() {
                 // Safety: Fd is guaranteed to be valid for any bit-pattern and we trust the OS to return a valid fd when using SCM_RIGHTS
-                self.rx_fd.push(unsafe { fd.assume_init().owned() });
+                let file = unsafe { fd.assume_init().owned() };
+                // A file descriptor that doesn't fit is closed as it is dropped
+                overflowed |= self.rx_fd.push(file).is_some();
+                fds += 1;
             }
         }
-        Ok(read != 0)
+        if let (Some(hook), Some(start)) = (&mut self.io_hook, start) {
+            hook(&IoEvent { send: false, bytes, fds, elapsed: start.elapsed() })
+        }
+        if overflowed {
+            // Dropping a file descriptor would give later requests the wrong files
+            return Err(crate::Error::Protocol(WlError::FD_OVERFLOW))
+        }
+        Ok(bytes != 0)
     }
 
-    pub fn sendmsg(&mut self) -> crate::Result<()> {
+    /// Send the committed messages and their file descriptors without blocking.
+    /// 
+    /// If the socket could not accept all of the data, the unsent remainder is retained and should be sent again
+    /// once the socket becomes writable.
+    pub fn sendmsg(&mut self) -> crate::Result<Flush> {
         use syslib::*;
-        let iov = [
-            IoVec::new(unsafe { std::slice::from_raw_parts(self.tx_msg.as_ptr() as *const u8, self.tx_msg.len() * size_of::<u32>()) })
-        ];
-        let mut ancillary = sock::Ancillary::<Fd, 8>::new();
-        let mut count = 8;
-        loop {
-            if let Some(item) = self.tx_fd.pop() {
-                ancillary.add_item(item);
-            } else {
+        let len = self.tx_committed * size_of::<u32>();
+        if self.tx_offset == len {
+            return Ok(Flush::Flushed)
+        }
+        // The file descriptors of a message must be sent no later than the message itself. Attach those of as many
+        // messages as fit, and stop before the first message whose file descriptors do not
+        let mut end = len;
+        let mut fds = 0;
+        let mut messages = 0;
+        for &(start, count) in &self.tx_fd_messages {
+            if fds + count > MAX_FDS_OUT {
+                end = start;
                 break
             }
-            if count == 0 {
-                break
+            fds += count;
+            messages += 1;
+        }
+        // Resume from where a previous partial send stopped, which may be part way through a word
+        let iov = [
+            IoVec::new(unsafe { &std::slice::from_raw_parts(self.tx_msg.as_ptr() as *const u8, len)[self.tx_offset..end] })
+        ];
+        let mut ancillary = sock::Ancillary::<Fd, MAX_FDS_OUT>::new();
+        // File descriptors are only removed from the queue once they have been sent
+        for fd in self.tx_fd.iter().take(fds) {
+            ancillary.add_item(fd.fd());
+        }
+        let start = self.io_hook.is_some().then(std::time::Instant::now);
+        let sent = match sendmsg(&self.socket, &iov, Some(&ancillary), sock::Flags::DONT_WAIT) {
+            Ok(sent) => sent,
+            Err(e) if e == Error::AGAIN => return Ok(Flush::Partial(len - self.tx_offset)),
+            Err(e) => return Err(e.into())
+        };
+        if let (Some(hook), Some(start)) = (&mut self.io_hook, start) {
+            hook(&IoEvent { send: true, bytes: sent, fds, elapsed: start.elapsed() })
+        }
+        // The file descriptors are sent along with the first byte
+        for _ in 0..fds {
+            self.tx_fd.pop();
+        }
+        self.tx_fd_messages.drain(..messages);
+        self.tx_offset += sent;
+        if self.tx_offset == len {
+            // An uncommitted message keeps its place so that its commit key remains valid
+            if self.tx_msg.len() == self.tx_committed {
+                self.tx_msg.clear();
+                self.tx_committed = 0;
+                self.tx_offset = 0;
             }
-            count -= 1
+            Ok(Flush::Flushed)
+        } else {
+            Ok(Flush::Partial(len - self.tx_offset))
         }
-        sendmsg(&self.socket, &iov, Some(&ancillary), sock::Flags::NONE)?;
-        self.tx_msg.clear();
-        Ok(())
     }
 }
 
@@ -507,7 +1403,9 @@ use std::mem::MaybeUninit;
 pub struct RingBuffer<T> {
     data: Box<[MaybeUninit<T>]>,
     front: usize,
-    back: usize
+    back: usize,
+    /// The number of pushes rejected because the buffer was full.
+    overflows: usize
 }
 impl<T> RingBuffer<T> {
     /// Create a new `RingBuffer` with the given size.
@@ -516,22 +1414,112 @@ impl<T> RingBuffer<T> {
     /// an overflow.
     /// 
     /// ## Panics
-    /// If `capacity` is not a multiple of 2.
+    /// If `capacity` is not a power of 2, or the buffer cannot be allocated.
     pub fn new(capacity: usize) -> Self {
+        assert!(capacity.is_power_of_two(), "Cannot construct a RingBuffer with a length of {capacity} as it is not a power of 2.");
+        Self::try_new(capacity).expect("Failed to allocate a RingBuffer.")
+    }
+    /// Create a new `RingBuffer` with the given size, or `None` if `capacity` is not a power of 2 or the buffer
+    /// cannot be allocated.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// assert!(RingBuffer::<u32>::try_new(0).is_none());
+    /// assert_eq!(RingBuffer::<u32>::try_new(1).unwrap().capacity(), 1);
+    /// assert!(RingBuffer::<u32>::try_new(3).is_none());
+    /// assert!(RingBuffer::<u32>::try_new(1 << 62).is_none());
+    /// assert!(RingBuffer::<u32>::try_new(usize::MAX / 2 + 1).is_none());
+    /// ```
+    pub fn try_new(capacity: usize) -> Option<Self> {
         if !capacity.is_power_of_two() {
-            panic!("Cannot construct a RingBuffer with a length of {capacity} as it is not a power of 2.")
+            return None
         }
-        let data = unsafe {
-            let layout = std::alloc::Layout::array::<MaybeUninit<T>>(capacity).unwrap();
-            let data = std::alloc::alloc(layout) as *mut MaybeUninit<T>;
-            let slice = std::slice::from_raw_parts_mut(data, capacity);
-            Box::from_raw(slice)
-        };
-        Self {
-            data,
+        Some(Self {
+            data: Self::allocate(capacity)?,
             front: 0,
-            back: 0
+            back: 0,
+            overflows: 0
+        })
+    }
+    /// Create a new `RingBuffer` able to hold at least `min` items, or `None` if the buffer cannot be allocated.
+    /// 
+    /// The capacity is the next power of 2 above `min`, leaving room for the slot that is always empty.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// assert_eq!(RingBuffer::<u8>::with_min_capacity(0).unwrap().capacity(), 1);
+    /// assert_eq!(RingBuffer::<u8>::with_min_capacity(1).unwrap().capacity(), 2);
+    /// assert_eq!(RingBuffer::<u8>::with_min_capacity(3).unwrap().capacity(), 4);
+    /// assert_eq!(RingBuffer::<u8>::with_min_capacity(4).unwrap().capacity(), 8);
+    /// assert!(RingBuffer::<u8>::with_min_capacity(3).unwrap().free() >= 3);
+    /// // Rounds up to 2^(usize::BITS - 1), which is too large to allocate
+    /// assert!(RingBuffer::<u8>::with_min_capacity(usize::MAX / 2).is_none());
+    /// assert!(RingBuffer::<u8>::with_min_capacity(usize::MAX).is_none());
+    /// ```
+    pub fn with_min_capacity(min: usize) -> Option<Self> {
+        let capacity = min.checked_add(1).and_then(usize::checked_next_power_of_two)?;
+        Self::try_new(capacity)
+    }
+    /// Allocate space for `capacity` items, or `None` if the size overflows or the allocator fails.
+    fn allocate(capacity: usize) -> Option<Box<[MaybeUninit<T>]>> {
+        let layout = std::alloc::Layout::array::<MaybeUninit<T>>(capacity).ok()?;
+        let data = if layout.size() == 0 {
+            // Zero-sized allocations must not be made through the allocator
+            std::ptr::NonNull::<MaybeUninit<T>>::dangling().as_ptr()
+        } else {
+            // Safety: The layout has a non-zero size
+            unsafe { std::alloc::alloc(layout) as *mut MaybeUninit<T> }
+        };
+        if data.is_null() {
+            return None
         }
+        // Safety: The allocation was made with the layout of a `[MaybeUninit<T>]` of length `capacity`, which is valid
+        // uninitialised, and so can be freed by the box
+        unsafe { Some(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, capacity))) }
+    }
+    /// Double the capacity of the `RingBuffer`, moving the items to the start of the new buffer.
+    /// 
+    /// The spare capacity is moved along with the items, so anything written to it but not yet added with
+    /// `advance_front` is preserved.
+    /// 
+    /// ## Panics
+    /// If the larger buffer cannot be allocated.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// let mut buf = RingBuffer::new(4);
+    /// buf.push(0);
+    /// buf.pop();
+    /// for i in 1..4 {
+    ///     buf.push(i);
+    /// }
+    /// buf.grow();
+    /// assert_eq!(buf.capacity(), 8);
+    /// assert_eq!(buf.iter().copied().collect::<Vec<_>>(), [1, 2, 3]);
+    /// ```
+    pub fn grow(&mut self) {
+        let capacity = self.data.len();
+        let mut data = capacity.checked_mul(2)
+            .and_then(Self::allocate)
+            .expect("Failed to grow a RingBuffer.");
+        let len = self.len();
+        // Safety: The slots are copied as `MaybeUninit`, so ownership of the items moves to the new buffer and
+        // the old buffer is freed without dropping them
+        unsafe {
+            let (wrapped, tail) = self.data.split_at(self.back);
+            std::ptr::copy_nonoverlapping(tail.as_ptr(), data.as_mut_ptr(), tail.len());
+            std::ptr::copy_nonoverlapping(wrapped.as_ptr(), data.as_mut_ptr().add(tail.len()), wrapped.len());
+        }
+        self.data = data;
+        self.back = 0;
+        self.front = len;
+    }
+    /// The number of times `push` has rejected an element because the buffer was full.
+    /// 
+    /// This is not reset by `clear`, allowing buffer sizes to be tuned over the lifetime of the buffer.
+    #[inline]
+    pub fn overflow_count(&self) -> usize {
+        self.overflows
     }
     pub fn iter(&self) -> RingBufferIter<'_, T> {
         RingBufferIter { ring_buffer: self, index: 0 }
@@ -544,6 +1532,7 @@ impl<T> RingBuffer<T> {
     pub fn push(&mut self, value: T) -> Option<T> {
         let next = self.increment(self.front);
         if next == self.back {
+            self.overflows += 1;
             Some(value)
         } else {
             self.data[self.front] = MaybeUninit::new(value);
@@ -551,6 +1540,52 @@ impl<T> RingBuffer<T> {
             None
         }
     }
+    /// Get the unused space in the `RingBuffer`, in the order it would be filled by `push`.
+    /// 
+    /// The space is split in to two slices as it may wrap around the end of the underlying buffer.
+    /// Once written to, the items can be added to the `RingBuffer` using `advance_front`.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// let mut buf = RingBuffer::new(8);
+    /// // Move the back pointer so that the spare capacity wraps around the end of the buffer
+    /// for i in 0..6 {
+    ///     buf.push(i);
+    ///     buf.pop();
+    /// }
+    /// buf.push(0);
+    /// let (first, second) = buf.spare_capacity_mut();
+    /// assert_eq!((first.len(), second.len()), (1, 5));
+    /// for (i, item) in first.iter_mut().chain(second.iter_mut()).take(4).enumerate() {
+    ///     item.write(i + 1);
+    /// }
+    /// unsafe { buf.advance_front(4) };
+    /// assert_eq!(buf.iter().copied().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+    /// ```
+    pub fn spare_capacity_mut(&mut self) -> (&mut [MaybeUninit<T>], &mut [MaybeUninit<T>]) {
+        let capacity = self.data.len();
+        // One slot is always left empty so that a full buffer can be distinguished from an empty one
+        if self.front < self.back {
+            (&mut self.data[self.front..self.back - 1], &mut [])
+        } else if self.back == 0 {
+            (&mut self.data[self.front..capacity - 1], &mut [])
+        } else {
+            let (wrapped, tail) = self.data.split_at_mut(self.front);
+            (tail, &mut wrapped[..self.back - 1])
+        }
+    }
+    /// Add `count` items written to the spare capacity to the `RingBuffer`.
+    /// 
+    /// ## Safety
+    /// The first `count` items of the spare capacity returned by `spare_capacity_mut` must be initialised.
+    /// 
+    /// ## Panics
+    /// If `count` is greater than the length of the spare capacity.
+    pub unsafe fn advance_front(&mut self, count: usize) {
+        let (first, second) = self.spare_capacity_mut();
+        assert!(count <= first.len() + second.len(), "Cannot advance the front of a RingBuffer beyond its spare capacity.");
+        self.front = (self.front + count) & (self.data.len() - 1);
+    }
     /// Remove the oldest item from the `RingBuffer` and return it.
     pub fn pop(&mut self) -> Option<T> {
         if self.front == self.back {
@@ -561,6 +1596,45 @@ impl<T> RingBuffer<T> {
             Some(unsafe { self.data[index].assume_init_read() })
         }
     }
+    /// Keep only the items for which `f` returns true, preserving their order.
+    /// 
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// let mut buf = RingBuffer::new(8);
+    /// // Move the back pointer so that the items wrap around the end of the buffer
+    /// for i in 0..6 {
+    ///     buf.push(i);
+    ///     buf.pop();
+    /// }
+    /// for i in 0..6 {
+    ///     buf.push(i);
+    /// }
+    /// buf.retain(|i| i % 2 == 0);
+    /// assert_eq!(buf.iter().copied().collect::<Vec<_>>(), [0, 2, 4]);
+    /// ```
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let len = self.len();
+        let mask = self.data.len() - 1;
+        // Should `f` panic the remaining items are leaked rather than dropped twice
+        self.front = self.back;
+        let mut kept = 0;
+        for i in 0..len {
+            let from = (self.back + i) & mask;
+            // Safety: The values in the range between `back` and the original `front` are initialised
+            if f(unsafe { self.data[from].assume_init_ref() }) {
+                let to = (self.back + kept) & mask;
+                if from != to {
+                    let value = unsafe { self.data[from].assume_init_read() };
+                    self.data[to] = MaybeUninit::new(value);
+                }
+                kept += 1;
+            } else {
+                unsafe { self.data[from].assume_init_drop() }
+            }
+        }
+        self.front = (self.back + kept) & mask;
+    }
     /// Remove all items from the `RingBuffer`.
     pub fn clear(&mut self) {
         // For types with no special drop this would be as simples as setting front & back to 0.
@@ -623,14 +1697,20 @@ impl<T> RingBuffer<T> {
         }
     }
     /// Return the number of items that can be inserted before the buffer is full.
+    /// 
+    /// This is the number of calls to `push` that will succeed, and the total length of `spare_capacity_mut`.
+    /// As one slot is always left empty it is at most one less than the capacity.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// let mut buf = RingBuffer::new(8);
+    /// buf.push(0);
+    /// let free = buf.free();
+    /// let pushed = (0..).take_while(|&i| buf.push(i).is_none()).count();
+    /// assert_eq!(free, pushed);
+    /// ```
     pub fn free(&self) -> usize {
-        self.data.len() - (
-            if self.front < self.back {
-                (self.front + self.data.len()) - self.back
-            } else {
-                self.front - self.back
-            }
-        )
+        self.data.len() - 1 - self.len()
     }
     /// Return the maximum number of items the RingBuffer` can hold.
     pub fn capacity(&self) -> usize {
@@ -641,8 +1721,22 @@ impl<T> RingBuffer<T> {
         self.front == self.back
     }
     /// Returns true if there is no more space to insert an item in to the `RingBuffer`, or false otherwise.
+    /// 
+    /// One slot is always left empty to distinguish a full buffer from an empty one, so a `RingBuffer` is full
+    /// once it holds one less item than its capacity.
+    /// ```rust
+    /// use yutani::wire::RingBuffer;
+    /// 
+    /// let mut buffer = RingBuffer::new(4);
+    /// for i in 0..3 {
+    ///     assert!(!buffer.is_full());
+    ///     assert_eq!(buffer.push(i), None);
+    /// }
+    /// assert!(buffer.is_full());
+    /// assert_eq!(buffer.push(3), Some(3));
+    /// ```
     pub fn is_full(&self) -> bool {
-        self.front == self.back
+        self.increment(self.front) == self.back
     }
 }
 impl<T> Drop for RingBuffer<T> {
@@ -663,7 +1757,8 @@ impl<T: Clone + Copy> Clone for RingBuffer<T> {
         Self {
             data: self.data.clone(),
             front: self.front,
-            back: self.back
+            back: self.back,
+            overflows: self.overflows
         }
     }
 }
@@ -698,4 +1793,860 @@ impl<'a, T> Iterator for RingBufferIterMut<'a, T> {
         self.index += 1;
         self.ring_buffer.get_mut(index).map(|i| unsafe { &mut *(i as *mut T) })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A socket path in the temporary directory that is unique to the test.
+    pub(crate) fn socket_path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("yutani-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+    /// Connect a pair of streams, returning the accepted end followed by the connecting end.
+    pub(crate) fn stream_pair() -> (Stream, Stream) {
+        let path = socket_path();
+        let server = Server::listen(&path).unwrap();
+        let client = Stream::connect(&path).unwrap();
+        let accepted = server.accept().unwrap();
+        (accepted, client)
+    }
+    /// Send raw words as committed messages, bypassing the checks of `start_message` and `commit`.
+    pub(crate) fn send_words(stream: &mut Stream, words: &[u32]) {
+        stream.tx_msg.extend_from_slice(words);
+        stream.tx_committed = stream.tx_msg.len();
+        assert!(stream.sendmsg().unwrap().is_flushed());
+    }
+    /// Build a message header word from its size in words and opcode.
+    pub(crate) fn header(words: usize, opcode: u16) -> u32 {
+        ((words * size_of::<u32>()) as u32) << 16 | opcode as u32
+    }
+    /// Build a `new_id` argument as `Stream::new_id` would read it.
+    pub(crate) fn new_id(id: u32, interface: &'static str, version: u32) -> NewId {
+        NewId { id: Id::new(id), version, interface: Cow::Borrowed(interface) }
+    }
+    /// The size of a received file, which identifies the message a memfd of distinct size was sent with.
+    fn file_size(file: &File) -> u64 {
+        std::fs::metadata(format!("/proc/self/fd/{}", file.fd().raw())).unwrap().len()
+    }
+
+    #[test]
+    fn modify_rejects_stale_token() {
+        use syslib::epoll::Events;
+        let mut event_loop = EventLoop::new(()).unwrap();
+        let token = event_loop.add(Box::new(Notifier::new(|_| ()).unwrap())).unwrap();
+        event_loop.modify(token, EventLoop::<()>::DEFAULT_EVENTS | Events::OUTPUT).unwrap();
+        // The token of the next source to use the file descriptor does not refer to the attached source
+        let fd = event_loop.source_mut(token).unwrap().fd();
+        let next = event_loop.token_for(fd);
+        assert!(matches!(event_loop.modify(next, Events::INPUT), Err(Error::NoSource)));
+    }
+    #[test]
+    fn remove_rejects_stale_token() {
+        let mut event_loop = EventLoop::new(()).unwrap();
+        let token = event_loop.add(Box::new(Notifier::new(|_| ()).unwrap())).unwrap();
+        assert!(event_loop.remove(token).unwrap().is_some());
+        assert!(matches!(event_loop.remove(token), Err(Error::NoSource)));
+        assert!(event_loop.is_empty());
+    }
+    #[test]
+    fn remove_while_dispatching() {
+        use std::{rc::Rc, cell::Cell};
+        let mut event_loop = EventLoop::new(0).unwrap();
+        let token = Rc::new(Cell::new(None));
+        let notifier = Notifier::new({
+            let token = token.clone();
+            move |event_loop: &mut EventLoop<u32>| {
+                event_loop.state += 1;
+                // The source being dispatched is leased out, so it is dropped once it returns
+                assert!(event_loop.remove(token.get().unwrap()).unwrap().is_none());
+            }
+        }).unwrap();
+        let handle = notifier.handle();
+        token.set(Some(event_loop.add(Box::new(notifier)).unwrap()));
+        handle.notify().unwrap();
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.state, 1);
+        assert!(!event_loop.contains(token.get().unwrap()));
+        assert!(event_loop.is_empty());
+    }
+    #[test]
+    fn invalid_utf8_string() {
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(6, 0), 3, u32::from_ne_bytes([b'a', 0xFF, 0, 0]), 2, u32::from_ne_bytes([b'a', 0, 0, 0])]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        match rx.string() {
+            Err(DispatchError::Utf8Error(error)) => assert_eq!(error.valid_up_to(), 1),
+            other => panic!("Expected a UTF-8 error, got {:?}", other)
+        }
+        assert_eq!(rx.string().unwrap().as_deref(), Some("a"));
+    }
+    #[test]
+    fn unknown_enum_value() {
+        #[derive(Debug, PartialEq)]
+        struct Even(u32);
+        impl TryFrom<u32> for Even {
+            type Error = ();
+            fn try_from(value: u32) -> Result<Self, ()> {
+                if value.is_multiple_of(2) { Ok(Self(value)) } else { Err(()) }
+            }
+        }
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(4, 0), 4, 5]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.uint_enum::<Even>().unwrap(), Even(4));
+        assert!(matches!(rx.uint_enum::<Even>(), Err(DispatchError::NoVariant { value: 5, .. })));
+    }
+    #[test]
+    fn fixed_accumulation_does_not_drift() {
+        // 1000 deltas of 0.3515625, as a relative pointer motion event might carry
+        let delta = Fixed::from_bits(90);
+        let mut sum = Fixed::from(0);
+        let mut reference = 0i32;
+        for i in 0..1000 {
+            sum = sum + delta * (1 + i % 3) - delta;
+            reference += 90 * (1 + i % 3) - 90;
+        }
+        assert_eq!(sum.to_bits(), reference);
+        assert_eq!(sum, Fixed::from_bits(reference));
+    }
+    #[test]
+    fn fixed_arithmetic_saturates() {
+        assert_eq!(-Fixed::MIN, Fixed::MAX);
+        assert_eq!(-Fixed::MAX, Fixed::from_bits(-i32::MAX));
+        assert_eq!(Fixed::MAX + Fixed::from(1), Fixed::MAX);
+        assert_eq!(Fixed::MIN - Fixed::from(1), Fixed::MIN);
+        assert_eq!(Fixed::from(1 << 20) * 16, Fixed::MAX);
+        assert_eq!(Fixed::from(-(1 << 20)) * 16, Fixed::MIN);
+        assert_eq!(Fixed::from_f64(-0.0), Fixed::from(0));
+        assert!(Fixed::from(-1) < Fixed::from_f64(-0.5));
+    }
+    #[test]
+    fn child_sources() {
+        let mut event_loop = EventLoop::new(()).unwrap();
+        let parent = event_loop.add(Box::new(Notifier::new(|_| ()).unwrap())).unwrap();
+        // Children removed on their own, such as per-keypress repeat timers, are forgotten by the parent
+        for _ in 0..100 {
+            let child = event_loop.add_child(parent, Box::new(Timer::once(Duration::from_secs(60), |_, _| ()).unwrap())).unwrap();
+            event_loop.remove(child).unwrap();
+        }
+        assert!(event_loop.children.is_empty() && event_loop.parents.is_empty());
+
+        let child = event_loop.add_child(parent, Box::new(Timer::once(Duration::ZERO, |_, _| panic!("The timer outlived its parent.")).unwrap())).unwrap();
+        event_loop.remove(parent).unwrap();
+        assert!(!event_loop.contains(child));
+        assert!(event_loop.is_empty());
+        assert!(event_loop.children.is_empty() && event_loop.parents.is_empty());
+        event_loop.wait(10).unwrap();
+    }
+    /// Get the bytes of a golden case encoded by libwayland.
+    pub(crate) fn golden(name: &str) -> Vec<u8> {
+        let hex = include_str!("../tests/golden/libwayland.hex")
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("No golden case named {}", name));
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+    /// Send the committed messages of `stream` and read the bytes that arrive at `peer`.
+    pub(crate) fn sent_bytes(stream: &mut Stream, peer: &Stream) -> Vec<u8> {
+        assert!(stream.sendmsg().unwrap().is_flushed());
+        let mut bytes = vec![0; MAX_MESSAGE_SIZE];
+        let len = syslib::read(&peer.socket, &mut bytes).unwrap();
+        bytes.truncate(len);
+        bytes
+    }
+    #[test]
+    fn matches_libwayland_framing() {
+        let (mut tx, rx) = stream_pair();
+
+        let key = tx.start_message(Id::new(2), 0);
+        tx.send_u32(1).unwrap();
+        tx.send_string(Some("wl_compositor")).unwrap();
+        tx.send_u32(4).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(sent_bytes(&mut tx, &rx), golden("wl_registry.global"));
+
+        let key = tx.start_message(Id::new(3), 0);
+        tx.send_u32(1234).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(sent_bytes(&mut tx, &rx), golden("wl_callback.done"));
+
+        let key = tx.start_message(Id::new(4), 0);
+        tx.send_string(Some("text/plain")).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(sent_bytes(&mut tx, &rx), golden("wl_data_offer.offer"));
+
+        let keys: Vec<u8> = [30u32, 48].iter().flat_map(|key| key.to_ne_bytes()).collect();
+        let key = tx.start_message(Id::new(6), 1);
+        tx.send_u32(7).unwrap();
+        tx.send_object(Some(Id::new(5))).unwrap();
+        tx.send_bytes(&keys).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(sent_bytes(&mut tx, &rx), golden("wl_keyboard.enter"));
+
+        let key = tx.start_message(Id::new(6), 1);
+        tx.send_u32(8).unwrap();
+        tx.send_object(Some(Id::new(5))).unwrap();
+        tx.send_bytes(&[]).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(sent_bytes(&mut tx, &rx), golden("wl_keyboard.enter.empty"));
+    }
+    #[test]
+    fn null_object_message() {
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[0, header(3, 0), 10, 1, header(2, 1)]);
+        assert!(rx.recvmsg().unwrap());
+        let error = rx.message().unwrap().unwrap_err();
+        assert!(matches!(error, DispatchError::NullTarget));
+        // It is reported as an invalid object, unlike a null argument
+        assert_eq!(WlError::from(error).error, display_error::INVALID_OBJECT);
+        // The arguments of the rejected message are skipped
+        let message = rx.message().unwrap().unwrap();
+        assert_eq!((message.object, message.opcode), (Id::DISPLAY, 1));
+    }
+    #[test]
+    fn unread_arguments_are_skipped() {
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(4, 0), 10, 11, 1, header(3, 1), 12]);
+        assert!(rx.recvmsg().unwrap());
+        let message = rx.message().unwrap().unwrap();
+        assert_eq!((message.opcode, rx.remaining()), (0, 2));
+        assert_eq!(rx.u32().unwrap(), 10);
+        let message = rx.message().unwrap().unwrap();
+        assert_eq!(message.opcode, 1);
+        assert_eq!(rx.u32().unwrap(), 12);
+        assert!(rx.message().is_none());
+    }
+    #[test]
+    fn partial_send_resumes() {
+        let (mut tx, mut rx) = stream_pair();
+        // Queue messages without reading until the socket buffer is full
+        let mut sent = 0;
+        let remaining = loop {
+            let key = tx.start_message(Id::DISPLAY, 0);
+            tx.send_u32(sent).unwrap();
+            tx.send_bytes(&[sent as u8; 1000]).unwrap();
+            tx.commit(key).unwrap();
+            sent += 1;
+            match tx.sendmsg().unwrap() {
+                Flush::Partial(remaining) => break remaining,
+                Flush::Flushed => assert!(sent < 10_000, "The socket never filled.")
+            }
+        };
+        assert!(remaining > 0);
+        // Every message arrives whole and in order once the peer reads
+        let mut received = 0;
+        while received < sent {
+            tx.sendmsg().unwrap();
+            // The connecting end blocks until more arrives
+            assert!(rx.recvmsg().unwrap());
+            while let Some(message) = rx.message() {
+                message.unwrap();
+                assert_eq!(rx.u32().unwrap(), received);
+                assert_eq!(rx.bytes().unwrap(), [received as u8; 1000]);
+                received += 1;
+            }
+        }
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert_eq!(rx.rx_msg.len(), 0);
+    }
+    #[test]
+    fn accepted_socket_flags() {
+        let (accepted, _peer) = stream_pair();
+        let flags: syslib::open::Flags = syslib::fcntl(&accepted.socket, syslib::Fcntl::GetFd).unwrap().try_into().unwrap();
+        assert!(flags.any(syslib::open::Flags::CLOSE_ON_EXEC));
+        // Reading with nothing sent fails rather than blocking
+        assert_eq!(syslib::read(&accepted.socket, &mut [0; 4]), Err(syslib::Error::AGAIN));
+    }
+    #[test]
+    fn fds_of_consecutive_requests() {
+        let (mut rx, mut tx) = stream_pair();
+        for bytes in [&b"abc"[..], b"defgh"] {
+            let key = tx.start_message(Id::DISPLAY, 0);
+            let len = tx.send_memfd(bytes).unwrap();
+            tx.send_u32(len).unwrap();
+            tx.commit(key).unwrap();
+        }
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert!(rx.recvmsg().unwrap());
+        assert_eq!(rx.pending_rx_fds(), 2);
+        for _ in 0..2 {
+            rx.message().unwrap().unwrap();
+            let len = rx.u32().unwrap();
+            assert_eq!(file_size(&rx.file().unwrap()), len as u64);
+        }
+        assert_eq!(rx.pending_rx_fds(), 0);
+    }
+    #[test]
+    fn fd_overflow_is_an_error() {
+        let (mut rx, mut tx) = stream_pair();
+        // Each send carries as many file descriptors as the queue holds, and none are consumed in between
+        for _ in 0..2 {
+            let key = tx.start_message(Id::DISPLAY, 0);
+            while tx.send_memfd(&[]).is_ok() {}
+            tx.commit(key).unwrap();
+            assert!(tx.sendmsg().unwrap().is_flushed());
+        }
+        assert!(rx.recvmsg().unwrap());
+        assert!(matches!(rx.recvmsg(), Err(Error::Protocol(error)) if error.error == WlError::FD_OVERFLOW.error));
+    }
+    #[test]
+    fn message_split_within_a_word() {
+        let (mut rx, tx) = stream_pair();
+        let bytes: Vec<u8> = [1, header(3, 0), 0x0403_0201].iter().flat_map(|word| word.to_ne_bytes()).collect();
+        for split in [&bytes[..6], &bytes[6..9], &bytes[9..]] {
+            assert!(rx.message().is_none());
+            assert_eq!(syslib::write(&tx.socket, split).unwrap(), split.len());
+            assert!(rx.recvmsg().unwrap());
+        }
+        let message = rx.message().unwrap().unwrap();
+        assert_eq!((message.object, message.opcode), (Id::DISPLAY, 0));
+        assert_eq!(rx.u32().unwrap(), 0x0403_0201);
+    }
+    #[test]
+    fn second_listen_fails() {
+        let path = socket_path();
+        let server = Server::listen(&path).unwrap();
+        assert!(matches!(Server::listen(&path), Err(Error::SocketInUse)));
+        // A server removes its socket and lock file as it is dropped
+        drop(server);
+        assert!(!path.exists());
+        assert!(!lock_path(&path).exists());
+        // A stale socket left by a server that exited without being dropped does not prevent another from listening
+        std::fs::write(&path, []).unwrap();
+        let server = Server::listen(&path).unwrap();
+        drop(Stream::connect(&path).unwrap());
+        server.accept().unwrap();
+    }
+    #[test]
+    fn timers() {
+        let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+        event_loop.add(Box::new(Timer::once(Duration::ZERO, |event_loop: &mut EventLoop<Vec<u64>>, expirations| {
+            event_loop.state.push(expirations)
+        }).unwrap())).unwrap();
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.state, [1]);
+        // A oneshot timer is not rearmed
+        event_loop.wait(20).unwrap();
+        assert_eq!(event_loop.state, [1]);
+
+        let mut event_loop = EventLoop::new(0).unwrap();
+        event_loop.add(Box::new(Timer::repeat(Duration::from_millis(1), |event_loop: &mut EventLoop<u64>, expirations| {
+            event_loop.state += expirations
+        }).unwrap())).unwrap();
+        while event_loop.state < 3 {
+            event_loop.wait(1000).unwrap();
+        }
+    }
+    #[test]
+    fn notify_from_another_thread() {
+        let mut event_loop = EventLoop::new(0).unwrap();
+        let notifier = Notifier::new(|event_loop: &mut EventLoop<u32>| event_loop.state += 1).unwrap();
+        let handle = notifier.handle();
+        event_loop.add(Box::new(notifier)).unwrap();
+        std::thread::spawn(move || handle.notify().unwrap()).join().unwrap();
+        event_loop.wait(1000).unwrap();
+        assert_eq!(event_loop.state, 1);
+    }
+    #[test]
+    fn tcp_transport() {
+        // Find a free port, as the listening address of a server is not exposed
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = Server::listen_tcp(address).unwrap();
+        let mut tx = Stream::connect_tcp(address).unwrap();
+        let mut rx = server.accept().unwrap();
+        assert_eq!((server.transport(), rx.transport()), (Transport::Tcp, Transport::Tcp));
+        assert!(server.socket_path().is_none());
+
+        let key = tx.start_message(Id::DISPLAY, 3);
+        tx.send_string(Some("tcp")).unwrap();
+        assert_eq!(tx.send_file(rx.socket.fd().extend()).unwrap_err().error, WlError::NO_FD_TRANSPORT.error);
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        let message = loop {
+            rx.recvmsg().unwrap();
+            if let Some(message) = rx.message() {
+                break message.unwrap()
+            }
+        };
+        assert_eq!(message.opcode, 3);
+        assert_eq!(rx.string().unwrap().as_deref(), Some("tcp"));
+    }
+    #[test]
+    fn owned_messages() {
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::DISPLAY, 2);
+        tx.send_u32(10).unwrap();
+        tx.send_u32(11).unwrap();
+        tx.send_memfd(b"fd").unwrap();
+        tx.commit(key).unwrap();
+        let key = tx.start_message(Id::new(3), 0);
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert!(rx.recvmsg().unwrap());
+        let messages: Vec<_> = rx.messages().map(Result::unwrap).collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].object, messages[0].opcode, &messages[0].args[..]), (Id::DISPLAY, 2, &[10, 11][..]));
+        assert_eq!((messages[1].object, messages[1].opcode, &messages[1].args[..]), (Id::new(3), 0, &[][..]));
+        // The file descriptor is counted, but is left for the consumer to take
+        assert_eq!((messages[0].pending_fds, messages[1].pending_fds), (1, 1));
+        assert_eq!(rx.pending_rx_fds(), 1);
+        assert!(rx.message().is_none());
+
+        send_words(&mut tx, &[0, header(2, 0)]);
+        assert!(rx.recvmsg().unwrap());
+        assert!(matches!(rx.messages().next(), Some(Err(DispatchError::NullTarget))));
+    }
+    #[test]
+    fn reset() {
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::DISPLAY, 0);
+        tx.send_memfd(b"fd").unwrap();
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        // A message and file descriptor queued but not sent are discarded
+        let key = tx.start_message(Id::DISPLAY, 1);
+        tx.send_memfd(b"fd").unwrap();
+        tx.commit(key).unwrap();
+        tx.reset();
+        assert_eq!(tx.pending_tx_fds(), 0);
+        assert!(rx.recvmsg().unwrap());
+        rx.reset();
+        assert_eq!(rx.pending_rx_fds(), 0);
+        assert!(rx.message().is_none());
+
+        // The connection remains usable
+        send_words(&mut tx, &[1, header(2, 2)]);
+        assert!(rx.recvmsg().unwrap());
+        assert_eq!(rx.message().unwrap().unwrap().opcode, 2);
+        assert!(rx.message().is_none());
+        assert_eq!(rx.pending_rx_fds(), 0);
+    }
+    #[test]
+    fn expect_end() {
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(3, 0), 10, 1, header(4, 0), 10, 11]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        rx.u32().unwrap();
+        rx.expect_end().unwrap();
+        // Reading beyond the end of a message does not consume the next
+        assert!(rx.u32().is_err());
+        rx.message().unwrap().unwrap();
+        rx.u32().unwrap();
+        assert_eq!(rx.remaining(), 1);
+        assert_eq!(rx.expect_end().unwrap_err().error, WlError::CORRUPT.error);
+    }
+    #[test]
+    fn static_and_dynamic_new_ids() {
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::new(2), 0);
+        tx.send_new_id(&new_id(5, "wl_seat", 7)).unwrap();
+        tx.send_object(Some(Id::new(6))).unwrap();
+        tx.send_object(None).unwrap();
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        let dynamic = rx.new_id().unwrap();
+        assert_eq!((dynamic.id(), dynamic.interface(), dynamic.version()), (Id::new(5), "wl_seat", 7));
+        let fixed = rx.new_id_static("wl_callback", 1).unwrap();
+        assert_eq!((fixed.id(), fixed.interface(), fixed.version()), (Id::new(6), "wl_callback", 1));
+        assert!(matches!(rx.new_id_static("wl_callback", 1), Err(DispatchError::ObjectNull)));
+        rx.expect_end().unwrap();
+    }
+    #[test]
+    fn fixed_float_range() {
+        let max = Fixed::MAX.to_f64();
+        let min = Fixed::MIN.to_f64();
+        assert_eq!(max, 8388608.0 - 1.0 / 256.0);
+        assert_eq!(min, -8388608.0);
+        for (value, expected) in [(max - 1.0 / 256.0, Fixed::from_bits(i32::MAX - 1)), (max, Fixed::MAX), (min, Fixed::MIN)] {
+            assert_eq!(Fixed::from_f64(value), expected);
+            assert_eq!(Fixed::try_from_f64(value).unwrap(), expected);
+        }
+        for (value, saturated) in [(max + 1.0, Fixed::MAX), (1e300, Fixed::MAX), (f64::INFINITY, Fixed::MAX), (min - 1.0, Fixed::MIN), (f64::NEG_INFINITY, Fixed::MIN)] {
+            assert_eq!(Fixed::from_f64(value), saturated);
+            assert_eq!(Fixed::try_from_f64(value).unwrap_err().error, WlError::DOMAIN.error);
+        }
+        assert_eq!(Fixed::from_f64(f64::NAN), Fixed::from(0));
+        assert!(Fixed::try_from_f64(f64::NAN).is_err());
+    }
+    #[test]
+    fn aborted_message_fds_are_not_sent() {
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::DISPLAY, 0);
+        tx.send_memfd(b"aborted").unwrap();
+        tx.abort(key);
+        // A message that is started and never committed is discarded by the next
+        let _ = tx.start_message(Id::DISPLAY, 0);
+        tx.send_memfd(b"abandoned").unwrap();
+        let key = tx.start_message(Id::DISPLAY, 1);
+        tx.send_u32(7).unwrap();
+        tx.commit(key).unwrap();
+        assert_eq!(tx.pending_tx_fds(), 0);
+        assert!(tx.sendmsg().unwrap().is_flushed());
+
+        assert!(rx.recvmsg().unwrap());
+        assert_eq!(rx.pending_rx_fds(), 0);
+        let message = rx.message().unwrap().unwrap();
+        assert_eq!((message.opcode, rx.u32().unwrap()), (1, 7));
+        assert!(rx.message().is_none());
+    }
+    #[test]
+    fn keymap_via_memfd() {
+        // Larger than any message, as only the file descriptor and size are sent inline
+        let keymap: Vec<u8> = (0..MAX_MESSAGE_SIZE * 4).map(|i| i as u8).collect();
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::new(3), 0);
+        tx.send_u32(1).unwrap();
+        let size = tx.send_memfd(&keymap).unwrap();
+        tx.send_u32(size).unwrap();
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.u32().unwrap(), 1);
+        let file = rx.file().unwrap();
+        let size = rx.u32().unwrap();
+        assert_eq!(size as usize, keymap.len());
+        let path = format!("/proc/self/fd/{}", file.fd().raw());
+        assert_eq!(std::fs::read(&path).unwrap(), keymap);
+        // The file is sealed against modification by the receiver
+        assert!(std::fs::OpenOptions::new().write(true).open(&path).and_then(|mut file| std::io::Write::write_all(&mut file, b"x")).is_err());
+    }
+    #[test]
+    fn negative_enum_value() {
+        #[derive(Debug, PartialEq)]
+        enum Transform { Flipped = -1, Normal = 0 }
+        impl TryFrom<i32> for Transform {
+            type Error = ();
+            fn try_from(value: i32) -> Result<Self, ()> {
+                match value {
+                    -1 => Ok(Self::Flipped),
+                    0 => Ok(Self::Normal),
+                    _ => Err(())
+                }
+            }
+        }
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(5, 0), -1i32 as u32, 0, -2i32 as u32]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.int_enum::<Transform>().unwrap(), Transform::Flipped);
+        assert_eq!(rx.int_enum::<Transform>().unwrap(), Transform::Normal);
+        assert!(matches!(rx.int_enum::<Transform>(), Err(DispatchError::NoVariant { value, .. }) if value as i32 == -2));
+    }
+    #[test]
+    fn overflow_count() {
+        let mut buf = RingBuffer::new(4);
+        for i in 0..3 {
+            assert_eq!(buf.push(i), None);
+        }
+        for i in 3..8 {
+            assert_eq!(buf.push(i), Some(i));
+        }
+        assert_eq!(buf.overflow_count(), 5);
+        // Clearing the buffer does not reset the count, and the count carries over to a clone
+        buf.clear();
+        assert_eq!(buf.push(0), None);
+        assert_eq!(buf.clone().overflow_count(), 5);
+    }
+    #[test]
+    fn io_hook() {
+        use std::{rc::Rc, cell::RefCell};
+        /// Whether each call was a send, with the bytes and file descriptors transferred.
+        type Events = Rc<RefCell<Vec<(bool, usize, usize)>>>;
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let hook = |events: &Events| -> IoHook {
+            let events = events.clone();
+            Box::new(move |event| events.borrow_mut().push((event.send, event.bytes, event.fds)))
+        };
+        let (mut server, mut client) = stream_pair();
+        server.set_io_hook(hook(&events));
+        client.set_io_hook(hook(&events));
+
+        let key = client.start_message(Id::DISPLAY, 0);
+        let size = client.send_memfd(b"keymap").unwrap();
+        client.send_u32(size).unwrap();
+        client.commit(key).unwrap();
+        assert!(client.sendmsg().unwrap().is_flushed());
+        assert!(server.recvmsg().unwrap());
+        let key = server.start_message(Id::DISPLAY, 0);
+        server.commit(key).unwrap();
+        assert!(server.sendmsg().unwrap().is_flushed());
+        assert!(client.recvmsg().unwrap());
+        assert_eq!(*events.borrow(), [(true, 12, 1), (false, 12, 1), (true, 8, 0), (false, 8, 0)]);
+
+        // Nothing is observed once the hook is removed
+        assert!(server.take_io_hook().is_some());
+        assert!(!server.recvmsg().unwrap());
+        assert_eq!(events.borrow().len(), 4);
+    }
+    #[test]
+    fn object_arguments() {
+        let (mut rx, mut tx) = stream_pair();
+        send_words(&mut tx, &[1, header(6, 0), 0, 7, 0, 8]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.object().unwrap(), None);
+        assert_eq!(rx.object().unwrap(), Some(Id::new(7)));
+        assert!(matches!(rx.non_nullable_object(), Err(DispatchError::ObjectNull)));
+        assert_eq!(rx.non_nullable_object().unwrap(), Id::new(8));
+    }
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "Invalid message commit key."))]
+    fn corrupt_commit_key() {
+        let (mut tx, _rx) = stream_pair();
+        let key = tx.start_message(Id::DISPLAY, 0);
+        // A key that does not point to the start of the message would give it a size of 4
+        let corrupt = CommitKey(key.0 + 1);
+        assert_eq!(tx.commit(corrupt).unwrap_err().error, WlError::INTERNAL.error);
+    }
+    #[test]
+    fn fixed_conversions() {
+        for value in [-1.5, 0.0, 1.5, 0.00390625, -8388608.0, 8388607.99609375] {
+            assert_eq!(Fixed::from_f64(value).to_f64(), value);
+        }
+        assert_eq!(Fixed::from_f64(-1.5).to_bits(), -384);
+        assert_eq!(Fixed::from_f64(-0.0), Fixed::from(0));
+        for (int, bits) in [(0, 0), (-1, -256), (8388607, 8388607 << 8), (-8388608, i32::MIN)] {
+            assert_eq!(Fixed::from(int).to_bits(), bits);
+            assert_eq!(i32::from(Fixed::from(int)), int);
+        }
+        // Integer conversion rounds towards zero
+        assert_eq!(i32::from(Fixed::from_f64(-1.5)), -1);
+        assert_eq!(i32::from(Fixed::from_f64(1.5)), 1);
+        assert_eq!(i32::from(Fixed::MAX), 8388607);
+
+        // Negative values are sign-extended through the wire
+        let (mut rx, mut tx) = stream_pair();
+        let key = tx.start_message(Id::DISPLAY, 0);
+        tx.send_fixed(Fixed::from_f64(-1.5)).unwrap();
+        tx.send_fixed(Fixed::MIN).unwrap();
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.fixed().unwrap().to_f64(), -1.5);
+        assert_eq!(rx.fixed().unwrap(), Fixed::MIN);
+    }
+    #[test]
+    fn message_larger_than_receive_buffer() {
+        let (mut rx, mut tx) = stream_pair();
+        let capacity = rx.rx_msg.capacity();
+        let args = MAX_MESSAGE_SIZE / size_of::<u32>() - 2;
+        assert!(args > capacity);
+        let key = tx.start_message(Id::DISPLAY, 0);
+        for i in 0..args {
+            tx.send_u32(i as u32).unwrap();
+        }
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+
+        let message = loop {
+            if let Some(message) = rx.message() {
+                break message.unwrap()
+            }
+            assert!(rx.recvmsg().unwrap());
+        };
+        assert_eq!(message.size as usize, MAX_MESSAGE_SIZE);
+        assert!(rx.rx_msg.capacity() > capacity);
+        for i in 0..args {
+            assert_eq!(rx.u32().unwrap(), i as u32);
+        }
+        assert!(rx.message().is_none());
+    }
+    #[test]
+    fn spurious_wake_up() {
+        let (mut rx, mut tx) = stream_pair();
+        // Reading the non-blocking accepted end with nothing sent fails with EAGAIN, which is not an error
+        for _ in 0..2 {
+            assert!(!rx.recvmsg().unwrap());
+            assert!(rx.message().is_none());
+        }
+        send_words(&mut tx, &[1, header(3, 0), 42]);
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.u32().unwrap(), 42);
+        assert!(!rx.recvmsg().unwrap());
+    }
+    #[test]
+    fn toggle_output_interest() {
+        use syslib::{eventfd, epoll::Events};
+        /// An eventfd with a zero count is always writable and never readable.
+        struct Writable(File);
+        impl EventSource<u32> for Writable {
+            fn fd(&self) -> Fd<'static> {
+                self.0.fd().extend()
+            }
+            fn input(&mut self, _: &mut EventLoop<u32>) -> crate::Result<()> {
+                panic!("Unexpected input")
+            }
+            fn output(&mut self, event_loop: &mut EventLoop<u32>) -> crate::Result<()> {
+                event_loop.state += 1;
+                Ok(())
+            }
+        }
+        let mut event_loop = EventLoop::new(0).unwrap();
+        let token = event_loop.add(Box::new(Writable(syslib::eventfd(0, eventfd::Flags::CLOSE_ON_EXEC | eventfd::Flags::NON_BLOCKING).unwrap()))).unwrap();
+        event_loop.wait(0).unwrap();
+        assert_eq!(event_loop.state, 0);
+        event_loop.modify(token, EventLoop::<u32>::DEFAULT_EVENTS | Events::OUTPUT).unwrap();
+        event_loop.wait(0).unwrap();
+        assert_eq!(event_loop.state, 1);
+        // Output interest is level-triggered, so it is reported until removed
+        event_loop.wait(0).unwrap();
+        assert_eq!(event_loop.state, 2);
+        event_loop.modify(token, EventLoop::<u32>::DEFAULT_EVENTS).unwrap();
+        event_loop.wait(0).unwrap();
+        assert_eq!(event_loop.state, 2);
+    }
+    #[test]
+    fn send_resumes_from_offset() {
+        let (mut tx, mut rx) = stream_pair();
+        let queue = |tx: &mut Stream, value: u32| {
+            let key = tx.start_message(Id::DISPLAY, 0);
+            tx.send_u32(value).unwrap();
+            tx.send_bytes(&[value as u8; 1000]).unwrap();
+            tx.commit(key).unwrap();
+        };
+        let mut sent = 0;
+        while tx.sendmsg().unwrap().is_flushed() {
+            queue(&mut tx, sent);
+            sent += 1;
+        }
+        for _ in 0..100 {
+            queue(&mut tx, sent);
+            sent += 1;
+        }
+        // Reading a little makes room for part of the queue, leaving the send to stop part way through it
+        assert!(rx.recvmsg().unwrap());
+        assert!(!tx.sendmsg().unwrap().is_flushed());
+        assert!(tx.tx_offset > 0);
+        // Messages committed and started while a send is partial are kept behind the unsent remainder
+        queue(&mut tx, sent);
+        sent += 1;
+        let key = tx.start_message(Id::DISPLAY, 0);
+        tx.send_u32(sent).unwrap();
+
+        let mut received = 0;
+        loop {
+            while let Some(message) = rx.message() {
+                message.unwrap();
+                assert_eq!(rx.u32().unwrap(), received);
+                assert_eq!(rx.bytes().unwrap(), [received as u8; 1000]);
+                received += 1;
+            }
+            if received == sent {
+                break
+            }
+            tx.sendmsg().unwrap();
+            assert!(rx.recvmsg().unwrap());
+        }
+        // The buffer is only compacted once drained, so the key of the message in progress remains valid
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert_eq!(tx.tx_offset, tx.tx_committed * size_of::<u32>());
+        tx.send_bytes(&[sent as u8; 1000]).unwrap();
+        tx.commit(key).unwrap();
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert_eq!((tx.tx_msg.len(), tx.tx_offset), (0, 0));
+        assert!(rx.recvmsg().unwrap());
+        rx.message().unwrap().unwrap();
+        assert_eq!(rx.u32().unwrap(), sent);
+    }
+    #[test]
+    fn reused_fd_rejects_old_token() {
+        use syslib::epoll::Events;
+        let mut event_loop = EventLoop::new(()).unwrap();
+        let old = event_loop.add(Box::new(Notifier::new(|_| ()).unwrap())).unwrap();
+        // Adding the removed source again attaches a source with the same file descriptor
+        let source = event_loop.remove(old).unwrap().unwrap();
+        let new = event_loop.add(source).unwrap();
+        assert_eq!(old.fd(), new.fd());
+        assert_ne!(old, new);
+        assert!(!event_loop.contains(old));
+        assert!(event_loop.source_mut(old).is_none());
+        assert!(matches!(event_loop.modify(old, Events::INPUT), Err(Error::NoSource)));
+        assert!(matches!(event_loop.remove(old), Err(Error::NoSource)));
+        // The new source is unaffected
+        event_loop.modify(new, EventLoop::<()>::DEFAULT_EVENTS).unwrap();
+        assert!(event_loop.remove(new).unwrap().is_some());
+    }
+    #[test]
+    fn display_error_codes() {
+        use display_error::*;
+        // The values of the `wl_display.error` enum in wayland.xml
+        assert_eq!([INVALID_OBJECT, INVALID_METHOD, NO_MEMORY, IMPLEMENTATION], [0, 1, 2, 3]);
+        for error in [WlError::NO_OBJECT, WlError::NULL_OBJECT, WlError::NO_GLOBAL, WlError::SERVER_ID] {
+            assert_eq!(error.error, INVALID_OBJECT, "{}", error.description);
+        }
+        for error in [WlError::CORRUPT, WlError::INVALID_OPCODE, WlError::NON_NULLABLE, WlError::UTF_8, WlError::DOMAIN] {
+            assert_eq!(error.error, INVALID_METHOD, "{}", error.description);
+        }
+        for error in [WlError::OOM, WlError::FD_OVERFLOW] {
+            assert_eq!(error.error, NO_MEMORY, "{}", error.description);
+        }
+        for error in [WlError::INTERNAL, WlError::NO_FD_TRANSPORT] {
+            assert_eq!(error.error, IMPLEMENTATION, "{}", error.description);
+        }
+        // Errors from decoding requests are reported against the display, whatever the object
+        for error in [DispatchError::ObjectNonExistent(Id::new(3)), DispatchError::ObjectNull, DispatchError::Protocol(WlError::INTERNAL)] {
+            assert_eq!(WlError::from(error).object, Id::DISPLAY);
+        }
+    }
+    #[test]
+    fn fixed_zero_is_canonical() {
+        use std::{hash::{Hash, Hasher}, collections::hash_map::DefaultHasher};
+        fn hash(fixed: Fixed) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            fixed.hash(&mut hasher);
+            hasher.finish()
+        }
+        let one = Fixed::from(1);
+        let zeros = [Fixed::from_f64(0.0), Fixed::from_f64(-0.0), Fixed::from_f64(-1e-9), Fixed::from(0), Fixed::from_bits(0), -Fixed::from(0), one - one, one * 2 - one - one];
+        for zero in zeros {
+            assert_eq!(zero, zeros[0]);
+            assert_eq!(hash(zero), hash(zeros[0]));
+            assert_eq!(zero.to_bits(), 0);
+        }
+    }
+    #[test]
+    fn fds_stay_with_their_messages() {
+        let (mut rx, mut tx) = stream_pair();
+        // Each message sends the sizes of its files, with a message without any in between
+        let messages: [&[usize]; 3] = [&[1, 2, 3], &[], &[4, 5, 6]];
+        for sizes in messages {
+            let key = tx.start_message(Id::DISPLAY, 0);
+            tx.send_u32(sizes.len() as u32).unwrap();
+            for &size in sizes {
+                tx.send_memfd(&vec![0; size]).unwrap();
+                tx.send_u32(size as u32).unwrap();
+            }
+            tx.commit(key).unwrap();
+        }
+        assert_eq!(tx.pending_tx_fds(), 6);
+        assert!(tx.sendmsg().unwrap().is_flushed());
+        assert_eq!(tx.pending_tx_fds(), 0);
+
+        assert!(rx.recvmsg().unwrap());
+        for sizes in messages {
+            rx.message().unwrap().unwrap();
+            assert_eq!(rx.u32().unwrap() as usize, sizes.len());
+            for &expected in sizes {
+                let file = rx.file().unwrap();
+                assert_eq!(rx.u32().unwrap() as usize, expected);
+                assert_eq!(file_size(&file), expected as u64);
+            }
+        }
+        assert!(rx.message().is_none());
+        assert_eq!(rx.pending_rx_fds(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+// Prints the bytes libwayland-server writes for a set of events, as the golden cases in `libwayland.hex`.
+// The few functions used are declared here, so only the library is needed to build it and not its headers.
+#include <stdio.h>
+#include <stdint.h>
+#include <stdarg.h>
+#include <sys/socket.h>
+#include <unistd.h>
+#include <string.h>
+
+struct wl_display; struct wl_client; struct wl_resource; struct wl_interface;
+struct wl_array { size_t size; size_t alloc; void *data; };
+extern const struct wl_interface wl_registry_interface, wl_callback_interface, wl_keyboard_interface, wl_surface_interface, wl_data_offer_interface;
+struct wl_display *wl_display_create(void);
+struct wl_client *wl_client_create(struct wl_display *, int);
+struct wl_resource *wl_client_get_object(struct wl_client *, uint32_t);
+struct wl_resource *wl_resource_create(struct wl_client *, const struct wl_interface *, int, uint32_t);
+void wl_resource_post_event(struct wl_resource *, uint32_t, ...);
+void wl_resource_post_error(struct wl_resource *, uint32_t, const char *, ...);
+void wl_client_flush(struct wl_client *);
+
+static int peer;
+static void dump(const char *name) {
+    unsigned char buf[4096];
+    ssize_t n = recv(peer, buf, sizeof buf, MSG_DONTWAIT);
+    printf("%s ", name);
+    for (ssize_t i = 0; i < n; i++) printf("%02x", buf[i]);
+    printf("\n");
+}
+int main(void) {
+    int fds[2];
+    socketpair(AF_UNIX, SOCK_STREAM, 0, fds);
+    peer = fds[1];
+    struct wl_display *display = wl_display_create();
+    struct wl_client *client = wl_client_create(display, fds[0]);
+
+    struct wl_resource *registry = wl_resource_create(client, &wl_registry_interface, 1, 2);
+    wl_resource_post_event(registry, 0, 1u, "wl_compositor", 4u);
+    wl_client_flush(client);
+    dump("wl_registry.global");
+
+    struct wl_resource *callback = wl_resource_create(client, &wl_callback_interface, 1, 3);
+    wl_resource_post_event(callback, 0, 1234u);
+    wl_client_flush(client);
+    dump("wl_callback.done");
+
+    struct wl_resource *offer = wl_resource_create(client, &wl_data_offer_interface, 1, 4);
+    wl_resource_post_event(offer, 0, "text/plain");
+    wl_client_flush(client);
+    dump("wl_data_offer.offer");
+
+    struct wl_resource *surface = wl_resource_create(client, &wl_surface_interface, 1, 5);
+    struct wl_resource *keyboard = wl_resource_create(client, &wl_keyboard_interface, 1, 6);
+    uint32_t keys[] = { 30, 48 };
+    struct wl_array array = { sizeof keys, sizeof keys, keys };
+    wl_resource_post_event(keyboard, 1, 7u, surface, &array);
+    wl_client_flush(client);
+    dump("wl_keyboard.enter");
+
+    struct wl_array empty = { 0, 0, NULL };
+    wl_resource_post_event(keyboard, 1, 8u, surface, &empty);
+    wl_client_flush(client);
+    dump("wl_keyboard.enter.empty");
+
+    struct wl_resource *display_resource = wl_client_get_object(client, 1);
+    wl_resource_post_error(display_resource, 1, "invalid method");
+    wl_client_flush(client);
+    dump("wl_display.error");
+    return 0;
+}
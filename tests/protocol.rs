@@ -0,0 +1,276 @@
+//! Requests decoded and events sent by the traits `#[protocol]` generates.
+
+use std::{cell::RefCell, io::Read, os::unix::net::UnixStream, path::Path, rc::Rc, sync::atomic::{AtomicUsize, Ordering}};
+
+use yutani::{
+    lease,
+    prelude::*,
+    server::{Client, ClientId, DispatchErrorHandler},
+    wire::{Server, Stream}
+};
+
+type State = Vec<String>;
+
+#[yutani::protocol("tests/protocol/example.toml")]
+mod example {
+    use super::*;
+
+    #[display]
+    impl ExFactory<State> for Factory {
+        fn create(_: Lease<Self>, event_loop: &mut EventLoop<State>, client: &mut Client<State>, id: NewId, label: Nullable<String>) -> Result<(), DispatchError> {
+            let item = Item { x: 0, scale: Fixed::from(1) };
+            client.insert(lease::Resident::new(id.id(), <Item as ExItem<State>>::dispatch, Item::INTERFACE, id.version(), item).into_any())?;
+            event_loop.state.push(format!("create {}@{} v{} {:?}", id.interface(), id.id(), id.version(), label));
+            Ok(())
+        }
+        fn r#move(_: Lease<Self>, event_loop: &mut EventLoop<State>, client: &mut Client<State>, item: Id, x: i32, scale: Fixed) -> Result<(), DispatchError> {
+            let mut item = client.get::<Item>(item)?;
+            item.x = x;
+            item.scale = scale;
+            event_loop.state.push(format!("move {} {}", item.x, item.scale.to_f64()));
+            Ok(())
+        }
+        fn attach(_: Lease<Self>, event_loop: &mut EventLoop<State>, _: &mut Client<State>, data: Vec<u8>, fd: File, size: u32, parent: Nullable<Id>) -> Result<(), DispatchError> {
+            let contents = std::fs::read_to_string(format!("/proc/self/fd/{}", fd.fd().raw())).unwrap();
+            assert_eq!(contents.len(), size as usize);
+            event_loop.state.push(format!("attach {:?} {:?} {:?}", data, contents, parent));
+            Ok(())
+        }
+    }
+}
+use example::{ExFactory, ExFactoryMode, ExItem, ExItemFlags};
+#[derive(Default)]
+struct Factory;
+struct Item {
+    x: i32,
+    scale: Fixed
+}
+impl ExItem<State> for Item {
+    fn destroy(this: Lease<Self>, event_loop: &mut EventLoop<State>, client: &mut Client<State>) -> Result<(), DispatchError> {
+        client.delete(&this)?;
+        event_loop.state.push(format!("destroy {}", this.id()));
+        Ok(())
+    }
+    fn configure(_: Lease<Self>, event_loop: &mut EventLoop<State>, _: &mut Client<State>, mode: ExFactoryMode, flags: ExItemFlags) -> Result<(), DispatchError> {
+        event_loop.state.push(format!("configure {} {} {}", mode.value(), flags.value(), flags.contains(ExItemFlags::URGENT)));
+        Ok(())
+    }
+}
+
+struct Recorder(Rc<RefCell<Vec<String>>>);
+impl DispatchErrorHandler<State> for Recorder {
+    fn handle(&mut self, _: &mut Client<State>, error: DispatchError) -> yutani::Result<()> {
+        self.0.borrow_mut().push(format!("{:?}", error));
+        Ok(())
+    }
+}
+
+#[yutani::protocol("tests/protocol/wayland.toml")]
+mod wayland {
+    use super::*;
+
+    #[display]
+    impl WlDisplay<State> for Display {
+        fn sync(_: Lease<Self>, _: &mut EventLoop<State>, client: &mut Client<State>, callback: NewId) -> Result<(), DispatchError> {
+            client.insert(lease::Resident::new(callback.id(), <Callback as WlCallback<State>>::dispatch, Callback::INTERFACE, 1, Callback).into_any())?;
+            Ok(())
+        }
+    }
+}
+use wayland::{WlCallback, WlCallbackEvents, WlDisplayError, WlDisplayEvents, WlSeatCapability, WlShmFormat, WlKeyboard, WlKeyboardEvents, WlSurface};
+#[derive(Default)]
+struct Display;
+struct Callback;
+impl WlCallback<State> for Callback {}
+struct Surface;
+impl WlSurface<State> for Surface {
+    fn destroy(this: Lease<Self>, _: &mut EventLoop<State>, client: &mut Client<State>) -> Result<(), DispatchError> {
+        client.delete(&this)?;
+        Ok(())
+    }
+}
+struct Keyboard;
+impl WlKeyboard<State> for Keyboard {
+    fn release(this: Lease<Self>, _: &mut EventLoop<State>, client: &mut Client<State>) -> Result<(), DispatchError> {
+        client.delete(&this)?;
+        Ok(())
+    }
+}
+
+/// Connect a pair of sockets, returning the accepted end followed by the connecting end.
+fn stream_pair<P>(connect: fn(&Path) -> P) -> (Stream, P) {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("yutani-protocol-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+    let _ = std::fs::remove_file(&path);
+    let server = Server::listen(&path).unwrap();
+    let peer = connect(&path);
+    let stream = server.accept().unwrap();
+    (stream, peer)
+}
+/// Connect a client with a factory as its display, returning the peer to send requests from.
+fn connect(errors: &Rc<RefCell<Vec<String>>>) -> (EventLoop<State>, ClientId, Stream) {
+    let (stream, peer) = stream_pair(|path| Stream::connect(path).unwrap());
+    let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+    let client = event_loop.add_client(stream, example::display).unwrap();
+    event_loop.with_client(client, |client| client.set_dispatch_error_handler(Box::new(Recorder(errors.clone())))).unwrap();
+    (event_loop, client, peer)
+}
+
+/// The bytes libwayland-server writes for an event, as captured in `tests/golden/libwayland.hex`.
+fn golden(name: &str) -> Vec<u8> {
+    let hex = include_str!("golden/libwayland.hex")
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("No golden case named {}", name));
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+/// Send the committed events of `client` and read the bytes that arrive at `peer`.
+fn sent_bytes(client: &mut Client<State>, peer: &mut UnixStream) -> Vec<u8> {
+    assert!(client.stream().sendmsg().unwrap().is_flushed());
+    let mut bytes = vec![0; 4096];
+    let len = peer.read(&mut bytes).unwrap();
+    bytes.truncate(len);
+    bytes
+}
+
+#[test]
+fn generated_dispatch() {
+    let errors = Rc::new(RefCell::new(Vec::new()));
+    let (mut event_loop, client, mut peer) = connect(&errors);
+    // The display is version 1, so `attach` is sent to another factory
+    let factory = Id::new(2);
+    event_loop.with_client(client, |client| {
+        client.insert(lease::Resident::new(factory, <Factory as ExFactory<State>>::dispatch, Factory::INTERFACE, 2, Factory).into_any())
+    }).unwrap().unwrap();
+    let item = Id::new(3);
+
+    let key = peer.start_message(Id::DISPLAY, 0);
+    peer.send_u32(item.into()).unwrap();
+    peer.send_string(Some("first")).unwrap();
+    peer.commit(key).unwrap();
+    let key = peer.start_message(Id::DISPLAY, 1);
+    peer.send_object(Some(item)).unwrap();
+    peer.send_i32(-4).unwrap();
+    peer.send_fixed(Fixed::from_f64(1.5)).unwrap();
+    peer.commit(key).unwrap();
+    let key = peer.start_message(factory, 2);
+    peer.send_bytes(&[1, 2, 3]).unwrap();
+    let size = peer.send_memfd(b"contents").unwrap();
+    peer.send_u32(size).unwrap();
+    peer.send_object(None).unwrap();
+    peer.commit(key).unwrap();
+    let key = peer.start_message(item, 1);
+    peer.send_i32(ExFactoryMode::_90 as i32).unwrap();
+    peer.send_u32(ExItemFlags::VISIBLE | ExItemFlags::URGENT).unwrap();
+    peer.commit(key).unwrap();
+    let key = peer.start_message(item, 0);
+    peer.commit(key).unwrap();
+    assert!(peer.sendmsg().unwrap().is_flushed());
+    event_loop.wait(1000).unwrap();
+
+    assert_eq!(event_loop.state, [
+        "create ex_item@3 v1 Some(\"first\")",
+        "move -4 1.5",
+        "attach [1, 2, 3] \"contents\" None",
+        "configure 1 5 true",
+        "destroy 3"
+    ]);
+    assert!(errors.borrow().is_empty());
+}
+
+#[test]
+fn generated_dispatch_errors() {
+    let errors = Rc::new(RefCell::new(Vec::new()));
+    let (mut event_loop, client, mut peer) = connect(&errors);
+    let item = Id::new(3);
+    event_loop.with_client(client, |client| {
+        let value = Item { x: 0, scale: Fixed::from(1) };
+        client.insert(lease::Resident::new(item, <Item as ExItem<State>>::dispatch, Item::INTERFACE, 1, value).into_any())
+    }).unwrap().unwrap();
+
+    // `attach` may not be sent to the display, as it is version 1
+    let key = peer.start_message(Id::DISPLAY, 2);
+    peer.send_bytes(&[1, 2, 3]).unwrap();
+    peer.commit(key).unwrap();
+    for item in [Id::DISPLAY, Id::new(9)] {
+        let key = peer.start_message(Id::DISPLAY, 1);
+        peer.send_object(Some(item)).unwrap();
+        peer.send_i32(0).unwrap();
+        peer.send_fixed(Fixed::from(1)).unwrap();
+        peer.commit(key).unwrap();
+    }
+    let key = peer.start_message(Id::DISPLAY, 0);
+    peer.send_object(None).unwrap();
+    peer.send_string(None).unwrap();
+    peer.commit(key).unwrap();
+    let key = peer.start_message(Id::DISPLAY, 3);
+    peer.commit(key).unwrap();
+    for (mode, flags) in [(5, ExItemFlags::VISIBLE), (ExFactoryMode::NORMAL, 8)] {
+        let key = peer.start_message(item, 1);
+        peer.send_u32(mode).unwrap();
+        peer.send_u32(flags).unwrap();
+        peer.commit(key).unwrap();
+    }
+    assert!(peer.sendmsg().unwrap().is_flushed());
+    event_loop.wait(1000).unwrap();
+
+    assert!(event_loop.state.is_empty());
+    assert_eq!(*errors.borrow(), [
+        r#"InvalidRequest { object: Id(1), interface: "ex_factory", opcode: 2 }"#,
+        r#"UnexpectedObjectType { object: Id(1), expected: "ex_item", received: "ex_factory" }"#,
+        "ObjectNonExistent(Id(9))",
+        "ObjectNull",
+        r#"InvalidRequest { object: Id(1), interface: "ex_factory", opcode: 3 }"#,
+        r#"NoVariant { name: "ex_factory.mode", value: 5 }"#,
+        r#"NoVariant { name: "ex_item.flags", value: 8 }"#
+    ]);
+}
+
+#[test]
+fn generated_events_match_libwayland() {
+    let (stream, mut peer) = stream_pair(|path| UnixStream::connect(path).unwrap());
+    let mut event_loop = EventLoop::new(Vec::new()).unwrap();
+    let client = event_loop.add_client(stream, wayland::display).unwrap();
+    event_loop.with_client(client, |client| {
+        client.insert(lease::Resident::new(Id::new(3), <Callback as WlCallback<State>>::dispatch, Callback::INTERFACE, 1, Callback).into_any()).unwrap();
+        client.insert(lease::Resident::new(Id::new(5), <Surface as WlSurface<State>>::dispatch, Surface::INTERFACE, 1, Surface).into_any()).unwrap();
+        client.insert(lease::Resident::new(Id::new(6), <Keyboard as WlKeyboard<State>>::dispatch, Keyboard::INTERFACE, 1, Keyboard).into_any()).unwrap();
+        let display = client.get::<Display>(Id::DISPLAY).unwrap();
+        let callback = client.get::<Callback>(Id::new(3)).unwrap();
+        let keyboard = client.get::<Keyboard>(Id::new(6)).unwrap();
+
+        callback.done(client, 1234).unwrap();
+        assert_eq!(sent_bytes(client, &mut peer), golden("wl_callback.done"));
+        let keys: Vec<u8> = [30u32, 48].iter().flat_map(|key| key.to_ne_bytes()).collect();
+        keyboard.enter(client, 7, Id::new(5), &keys).unwrap();
+        assert_eq!(sent_bytes(client, &mut peer), golden("wl_keyboard.enter"));
+        keyboard.enter(client, 8, Id::new(5), &[]).unwrap();
+        assert_eq!(sent_bytes(client, &mut peer), golden("wl_keyboard.enter.empty"));
+        // `repeat_info` was added in version 4, so is not sent to this keyboard
+        keyboard.repeat_info(client, 25, 600).unwrap();
+        display.error(client, Id::DISPLAY, WlDisplayError::INVALID_METHOD, "invalid method").unwrap();
+        assert_eq!(sent_bytes(client, &mut peer), golden("wl_display.error"));
+    }).unwrap();
+}
+
+#[test]
+fn enum_values() {
+    assert_eq!(WlShmFormat::from_u32(WlShmFormat::XRGB8888).unwrap().value(), 1);
+    assert_eq!(WlShmFormat::C8, 0x20203843);
+    assert_eq!(ExFactoryMode::_90, 1);
+    assert!(matches!(WlShmFormat::from_u32(2), Err(DispatchError::NoVariant { name: "wl_shm.format", value: 2 })));
+    let format = WlShmFormat::try_from(WlShmFormat::ARGB8888).unwrap();
+    assert!(matches!(format.value(), WlShmFormat::ARGB8888 | WlShmFormat::XRGB8888));
+
+    // A bitfield is valid for any combination of its flags, including none
+    let capabilities = WlSeatCapability::from_u32(WlSeatCapability::POINTER | WlSeatCapability::TOUCH).unwrap();
+    assert!(capabilities.contains(WlSeatCapability::POINTER | WlSeatCapability::TOUCH));
+    assert!(!capabilities.contains(WlSeatCapability::KEYBOARD));
+    let all = capabilities | WlSeatCapability::from_u32(WlSeatCapability::KEYBOARD).unwrap();
+    assert_eq!(all.value(), 7);
+    assert_eq!(u32::from(WlSeatCapability::from_u32(0).unwrap()), 0);
+    for value in [8, 9] {
+        assert!(matches!(WlSeatCapability::from_u32(value), Err(DispatchError::NoVariant { name: "wl_seat.capability", .. })));
+    }
+}
@@ -0,0 +1,6 @@
+//! Diagnostics reported by `#[protocol]`.
+
+#[test]
+fn ui() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,17 @@
+// trybuild compiles this from its own crate within the target directory
+#[yutani::protocol("../../../../tests/protocol/example.toml")]
+mod example {
+    pub struct A;
+
+    #[display]
+    impl ExItem<()> for A {
+        fn destroy(_: yutani::Lease<Self>, _: &mut yutani::EventLoop<()>, _: &mut yutani::server::Client<()>) -> Result<(), yutani::DispatchError> {
+            Ok(())
+        }
+        fn configure(_: yutani::Lease<Self>, _: &mut yutani::EventLoop<()>, _: &mut yutani::server::Client<()>, _: ExFactoryMode, _: ExItemFlags) -> Result<(), yutani::DispatchError> {
+            Ok(())
+        }
+    }
+}
+
+fn main() {}
@@ -0,0 +1,22 @@
+#[yutani::protocol("tests/protocol/example.toml")]
+mod example {
+    #[derive(Default)]
+    pub struct A;
+    #[derive(Default)]
+    pub struct B;
+
+    #[display]
+    impl ExItem<()> for A {
+        fn destroy(_: yutani::Lease<Self>, _: &mut yutani::EventLoop<()>, _: &mut yutani::server::Client<()>) -> Result<(), yutani::DispatchError> {
+            Ok(())
+        }
+    }
+    #[display]
+    impl ExItem<()> for B {
+        fn destroy(_: yutani::Lease<Self>, _: &mut yutani::EventLoop<()>, _: &mut yutani::server::Client<()>) -> Result<(), yutani::DispatchError> {
+            Ok(())
+        }
+    }
+}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[yutani::protocol("tests/protocol/example.toml")]
+mod example {}
+
+fn main() {}